@@ -2,10 +2,12 @@
 #![no_main]
 
 use aya_ebpf::bindings::{
-    BPF_DEVCG_ACC_MKNOD, BPF_DEVCG_DEV_BLOCK, BPF_DEVCG_DEV_CHAR, BPF_F_NO_PREALLOC,
+    BPF_DEVCG_ACC_MKNOD, BPF_DEVCG_ACC_READ, BPF_DEVCG_ACC_WRITE, BPF_DEVCG_DEV_BLOCK,
+    BPF_DEVCG_DEV_CHAR, BPF_F_NO_PREALLOC, BPF_NOEXIST,
 };
+use aya_ebpf::helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_uid_gid};
 use aya_ebpf::macros::{cgroup_device, map};
-use aya_ebpf::maps::HashMap;
+use aya_ebpf::maps::{HashMap, LruPerCpuHashMap, RingBuf};
 use aya_ebpf::programs::DeviceContext;
 
 #[repr(C)]
@@ -65,12 +67,150 @@ const DEV_PTMX: Device = Device {
     minor: 2,
 };
 
+/// Wildcard for major or minor numbers in `DEVICE_PERM` keys.
+const WILDCARD: u32 = u32::MAX;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DeviceMajor {
+    ty: u32,
+    major: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MinorRange {
+    /// First minor number of the range, inclusive.
+    start: u32,
+    /// Last minor number of the range, inclusive.
+    end: u32,
+    access: u32,
+}
+
+/// Maximum number of minor ranges per major number.
+const MAX_RANGES: usize = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MinorRanges {
+    count: u32,
+    /// Non-overlapping ranges, only the first `count` are valid.
+    ranges: [MinorRange; MAX_RANGES],
+}
+
 #[map(name = "DEVICE_PERM")]
 /// Hashmap storing a device -> permission mapping.
 ///
+/// Major and minor numbers in keys may be `WILDCARD`.
 /// This is modified from user-space to change permission.
 static DEVICE_PERM: HashMap<Device, u32> = HashMap::with_max_entries(256, BPF_F_NO_PREALLOC);
 
+#[map(name = "DEVICE_RANGE")]
+/// Hashmap storing a (type, major) -> minor ranges mapping.
+///
+/// This is modified from user-space to change permission.
+static DEVICE_RANGE: HashMap<DeviceMajor, MinorRanges> =
+    HashMap::with_max_entries(64, BPF_F_NO_PREALLOC);
+
+/// Maximum number of UIDs and GIDs each that a device can be restricted to.
+const MAX_OWNERS: usize = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DeviceOwners {
+    uid_count: u32,
+    gid_count: u32,
+    /// Only the first `uid_count` are valid.
+    uids: [u32; MAX_OWNERS],
+    /// Only the first `gid_count` are valid.
+    gids: [u32; MAX_OWNERS],
+}
+
+#[map(name = "DEVICE_OWNER")]
+/// Hashmap storing a device -> owners mapping.
+///
+/// Read and write access to devices present in this map is further restricted to processes whose
+/// UID or GID is one of the owners. IDs are as seen from the initial user namespace.
+/// This is modified from user-space to change permission.
+static DEVICE_OWNER: HashMap<Device, DeviceOwners> =
+    HashMap::with_max_entries(256, BPF_F_NO_PREALLOC);
+
+/// A denied device access.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Denial {
+    ty: u32,
+    major: u32,
+    minor: u32,
+    access: u32,
+    /// Thread group ID of the process.
+    pid: u32,
+    comm: [u8; 16],
+}
+
+#[map(name = "DENIALS")]
+/// Ring buffer of denied accesses.
+///
+/// This is consumed from user-space for auditing.
+static DENIALS: RingBuf = RingBuf::with_byte_size(64 * 1024, 0);
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct AccessCounters {
+    read: u64,
+    write: u64,
+    mknod: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DeviceCounters {
+    allowed: AccessCounters,
+    denied: AccessCounters,
+}
+
+#[map(name = "DEVICE_COUNTERS")]
+/// Per-CPU hashmap storing a device -> access check counters mapping.
+///
+/// This is read from user-space for usage statistics. Least recently checked devices are evicted
+/// when the map is full.
+static DEVICE_COUNTERS: LruPerCpuHashMap<Device, DeviceCounters> =
+    LruPerCpuHashMap::with_max_entries(1024, 0);
+
+fn count(dev: &Device, access: u32, allowed: bool) {
+    let counters = match DEVICE_COUNTERS.get_ptr_mut(dev) {
+        Some(counters) => counters,
+        None => {
+            let _ = DEVICE_COUNTERS.insert(dev, &DeviceCounters::default(), BPF_NOEXIST as u64);
+            let Some(counters) = DEVICE_COUNTERS.get_ptr_mut(dev) else {
+                return;
+            };
+            counters
+        }
+    };
+    // SAFETY: The value is per-CPU, and BPF programs are not preempted, so there are no
+    // concurrent writers.
+    let counters = unsafe { &mut *counters };
+    let counters = if allowed {
+        &mut counters.allowed
+    } else {
+        &mut counters.denied
+    };
+    if access & BPF_DEVCG_ACC_READ != 0 {
+        counters.read += 1;
+    }
+    if access & BPF_DEVCG_ACC_WRITE != 0 {
+        counters.write += 1;
+    }
+    if access & BPF_DEVCG_ACC_MKNOD != 0 {
+        counters.mknod += 1;
+    }
+}
+
+fn check_access(perm: u32, access: u32) -> bool {
+    perm & access == access
+}
+
 #[cgroup_device]
 fn check_device(ctx: DeviceContext) -> i32 {
     // SAFETY: This is a POD supplied by the kernel.
@@ -83,44 +223,112 @@ fn check_device(ctx: DeviceContext) -> i32 {
     };
     let access = ctx_dev.access_type >> 16;
 
+    let allowed = is_allowed(dev, access) && is_owner(&dev, access);
+    count(&dev, access, allowed);
+    if allowed {
+        return 1;
+    }
+
+    let denial = Denial {
+        ty: dev.ty,
+        major: dev.major,
+        minor: dev.minor,
+        access,
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        comm: bpf_get_current_comm().unwrap_or_default(),
+    };
+    // If the ring buffer is full, the denial is dropped; the access is denied regardless.
+    let _ = DENIALS.output(&denial, 0);
+    0
+}
+
+fn is_owner(dev: &Device, access: u32) -> bool {
+    if access == BPF_DEVCG_ACC_MKNOD {
+        return true;
+    }
+
+    // SAFETY: we have BPF_F_NO_PREALLOC enabled so the map is safe to access concurrently.
+    let Some(owners) = (unsafe { DEVICE_OWNER.get(dev) }) else {
+        return true;
+    };
+    let uid_gid = bpf_get_current_uid_gid();
+    let uid = uid_gid as u32;
+    let gid = (uid_gid >> 32) as u32;
+    owners
+        .uids
+        .iter()
+        .take(owners.uid_count as usize)
+        .any(|owner| *owner == uid)
+        || owners
+            .gids
+            .iter()
+            .take(owners.gid_count as usize)
+            .any(|owner| *owner == gid)
+}
+
+fn is_allowed(dev: Device, access: u32) -> bool {
     // Always allow mknod, we restrict on access not on creation.
     // This is consistent with eBPF genereated by Docker.
     if matches!(dev.ty, BPF_DEVCG_DEV_BLOCK | BPF_DEVCG_DEV_CHAR) && access == BPF_DEVCG_ACC_MKNOD {
-        return 1;
+        return true;
     }
 
     // Allow default devices for containers
     // https://github.com/opencontainers/runtime-spec/blob/main/config-linux.md
     match dev {
-        DEV_NULL | DEV_ZERO | DEV_FULL | DEV_RANDOM | DEV_URANDOM => return 1,
-        DEV_TTY | DEV_CONSOLE | DEV_PTMX => return 1,
+        DEV_NULL | DEV_ZERO | DEV_FULL | DEV_RANDOM | DEV_URANDOM => return true,
+        DEV_TTY | DEV_CONSOLE | DEV_PTMX => return true,
         // Pseudo-PTY
         Device {
             ty: BPF_DEVCG_DEV_CHAR,
             major: 136,
             minor: _,
-        } => return 1,
+        } => return true,
         _ => (),
     }
 
-    // For extra devices, check the map.
+    // For extra devices, check the maps. Exact entries take precedence over minor ranges, which
+    // take precedence over wildcards.
     // SAFETY: we have BPF_F_NO_PREALLOC enabled so the map is safe to access concurrently.
-    let mut device_perm = unsafe { DEVICE_PERM.get(&dev).copied() };
-    if device_perm.is_none() {
-        // If the device is not explicitly specified, use a special device (0, 0) to find the
-        // default permission to usee.
-        device_perm = unsafe {
-            DEVICE_PERM.get(&Device {
-                ty: dev.ty,
-                major: 0,
-                minor: 0,
-            }).copied()
-        };
+    if let Some(perm) = unsafe { DEVICE_PERM.get(&dev) } {
+        return check_access(*perm, access);
     }
-    match device_perm {
-        Some(perm) => (perm & access == access) as i32,
-        None => 0,
+
+    let major = DeviceMajor {
+        ty: dev.ty,
+        major: dev.major,
+    };
+    // SAFETY: we have BPF_F_NO_PREALLOC enabled so the map is safe to access concurrently.
+    if let Some(ranges) = unsafe { DEVICE_RANGE.get(&major) } {
+        for range in ranges.ranges.iter().take(ranges.count as usize) {
+            if range.start <= dev.minor && dev.minor <= range.end {
+                return check_access(range.access, access);
+            }
+        }
+    }
+
+    let candidates = [
+        Device {
+            minor: WILDCARD,
+            ..dev
+        },
+        Device {
+            major: WILDCARD,
+            ..dev
+        },
+        Device {
+            major: WILDCARD,
+            minor: WILDCARD,
+            ..dev
+        },
+    ];
+    for candidate in candidates {
+        // SAFETY: we have BPF_F_NO_PREALLOC enabled so the map is safe to access concurrently.
+        if let Some(perm) = unsafe { DEVICE_PERM.get(&candidate) } {
+            return check_access(*perm, access);
+        }
     }
+    false
 }
 
 #[panic_handler]
@@ -1,14 +1,22 @@
-use anyhow::{Context, Result};
-use aya::maps::{HashMap, MapData};
-use aya::programs::{CgroupAttachMode, CgroupDevice, Link};
+use anyhow::{Context, Result, bail, ensure};
+use aya::maps::{HashMap, MapData, MapError, PerCpuHashMap, RingBuf};
+use aya::programs::{CgroupDevice, Link};
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::mem::ManuallyDrop;
+use std::ops::RangeInclusive;
+use std::os::fd::AsFd;
 use std::path::{Path, PathBuf};
 
+use tokio::io::unix::AsyncFd;
+use zerocopy::{FromBytes, Immutable, KnownLayout};
+
+use crate::cli::DeviceFilter;
+
 // The numerical representation below needs to match BPF_DEVCG constants.
 #[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum DeviceType {
     Block = 1,
     Character = 2,
@@ -23,8 +31,35 @@ bitflags::bitflags! {
     }
 }
 
+/// A set of devices of the same type that permission is granted to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSet {
+    /// A single device.
+    Device(u32, u32),
+    /// A range of minor numbers of a major number.
+    Range(u32, RangeInclusive<u32>),
+    /// All minor numbers of a major number.
+    Major(u32),
+}
+
+/// A device cgroup rule, in the same form as `linux.resources.devices` of the OCI config.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceRule {
+    pub allow: bool,
+    /// `None` matches both block and character devices.
+    pub ty: Option<DeviceType>,
+    /// `None` matches all major numbers.
+    pub major: Option<u32>,
+    /// `None` matches all minor numbers.
+    pub minor: Option<u32>,
+    pub access: Access,
+}
+
+// This needs to match the BPF program.
+const WILDCARD: u32 = u32::MAX;
+
 #[repr(C)] // This is read as POD by the BPF program.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct Device {
     device_type: u32,
     major: u32,
@@ -34,35 +69,511 @@ struct Device {
 // SAFETY: Device is `repr(C)`` and has no padding.
 unsafe impl aya::Pod for Device {}
 
+#[repr(C)] // This is read as POD by the BPF program.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct DeviceMajor {
+    device_type: u32,
+    major: u32,
+}
+
+// SAFETY: DeviceMajor is `repr(C)` and has no padding.
+unsafe impl aya::Pod for DeviceMajor {}
+
+#[repr(C)] // This is read as POD by the BPF program.
+#[derive(Clone, Copy, Default)]
+struct MinorRange {
+    start: u32,
+    end: u32,
+    access: u32,
+}
+
+// This needs to match the BPF program.
+const MAX_RANGES: usize = 8;
+
+#[repr(C)] // This is read as POD by the BPF program.
+#[derive(Clone, Copy)]
+struct MinorRanges {
+    count: u32,
+    ranges: [MinorRange; MAX_RANGES],
+}
+
+// SAFETY: MinorRanges is `repr(C)` and has no padding.
+unsafe impl aya::Pod for MinorRanges {}
+
+// This needs to match the BPF program.
+const MAX_OWNERS: usize = 8;
+
+#[repr(C)] // This is read as POD by the BPF program.
+#[derive(Clone, Copy, Default)]
+struct DeviceOwners {
+    uid_count: u32,
+    gid_count: u32,
+    uids: [u32; MAX_OWNERS],
+    gids: [u32; MAX_OWNERS],
+}
+
+// SAFETY: DeviceOwners is `repr(C)` and has no padding.
+unsafe impl aya::Pod for DeviceOwners {}
+
+impl Device {
+    fn covers(&self, other: &Device) -> bool {
+        self.device_type == other.device_type
+            && (self.major == WILDCARD || self.major == other.major)
+            && (self.minor == WILDCARD || self.minor == other.minor)
+    }
+
+    /// The most general key that is covered by both keys.
+    fn intersect(&self, other: &Device) -> Option<Device> {
+        if self.device_type != other.device_type {
+            return None;
+        }
+        let intersect = |a: u32, b: u32| match (a, b) {
+            (WILDCARD, x) | (x, WILDCARD) => Some(x),
+            (a, b) if a == b => Some(a),
+            _ => None,
+        };
+        Some(Device {
+            device_type: self.device_type,
+            major: intersect(self.major, other.major)?,
+            minor: intersect(self.minor, other.minor)?,
+        })
+    }
+
+    /// Keys that the BPF program looks up for this key, in order of precedence.
+    fn candidates(&self) -> [Device; 4] {
+        [
+            *self,
+            Device {
+                minor: WILDCARD,
+                ..*self
+            },
+            Device {
+                major: WILDCARD,
+                ..*self
+            },
+            Device {
+                major: WILDCARD,
+                minor: WILDCARD,
+                ..*self
+            },
+        ]
+    }
+}
+
+/// Compile an ordered list of device cgroup rules into `DEVICE_PERM` map entries.
+///
+/// Rules are applied in order and later rules override earlier ones, while the BPF program
+/// instead picks the most specific matching entry. To reconcile the two, whenever a rule is
+/// applied, entries for all keys it overlaps with are materialized and updated too.
+fn compile_rules(rules: &[DeviceRule]) -> BTreeMap<Device, Access> {
+    let mut map = BTreeMap::<Device, Access>::new();
+    for rule in rules {
+        let types = match rule.ty {
+            Some(ty) => &[ty][..],
+            None => &[DeviceType::Block, DeviceType::Character][..],
+        };
+        for &ty in types {
+            let key = Device {
+                device_type: ty as u32,
+                major: rule.major.unwrap_or(WILDCARD),
+                minor: rule.minor.unwrap_or(WILDCARD),
+            };
+
+            let keys: Vec<_> = std::iter::once(key)
+                .chain(map.keys().filter_map(|existing| existing.intersect(&key)))
+                .collect();
+            for key in keys {
+                if !map.contains_key(&key) {
+                    let effective = key
+                        .candidates()
+                        .iter()
+                        .find_map(|candidate| map.get(candidate).copied())
+                        .unwrap_or(Access::empty());
+                    map.insert(key, effective);
+                }
+            }
+
+            for (existing, access) in map.iter_mut() {
+                if key.covers(existing) {
+                    if rule.allow {
+                        *access |= rule.access;
+                    } else {
+                        *access -= rule.access;
+                    }
+                }
+            }
+        }
+    }
+    map
+}
+
+/// Adapt compiled rules for a filter that runs alongside the runtime's own programs.
+///
+/// The kernel runs all programs attached to a cgroup and only allows an access if every program
+/// allows it, so the runtime's programs already enforce the container's rules. Wildcard rules
+/// are instead used to mark device classes whose devices are managed by us; these are denied by
+/// our program unless a device is hot-plugged. Any access not covered by rules is left for the
+/// runtime's programs to decide.
+fn cooperative_rules(mut map: BTreeMap<Device, Access>) -> BTreeMap<Device, Access> {
+    for (device, access) in map.iter_mut() {
+        if (device.major == WILDCARD) != (device.minor == WILDCARD) {
+            *access = Access::empty();
+        }
+    }
+    // Runtimes start with a deny-all rule, which must not be enforced by our program.
+    for ty in [DeviceType::Block, DeviceType::Character] {
+        map.insert(
+            Device {
+                device_type: ty as u32,
+                major: WILDCARD,
+                minor: WILDCARD,
+            },
+            Access::all(),
+        );
+    }
+    map
+}
+
+/// Number of access checks of each type.
+#[repr(C)] // This is written as POD by the BPF program.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccessCounters {
+    pub read: u64,
+    pub write: u64,
+    pub mknod: u64,
+}
+
+impl AccessCounters {
+    fn add(&mut self, other: &AccessCounters) {
+        self.read += other.read;
+        self.write += other.write;
+        self.mknod += other.mknod;
+    }
+}
+
+/// Number of allowed and denied access checks of a device.
+#[repr(C)] // This is written as POD by the BPF program.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceCounters {
+    pub allowed: AccessCounters,
+    pub denied: AccessCounters,
+}
+
+// SAFETY: DeviceCounters is `repr(C)` and has no padding.
+unsafe impl aya::Pod for DeviceCounters {}
+
+/// Access check counters of a device.
+#[derive(Debug, Clone)]
+pub struct DeviceUsage {
+    pub ty: DeviceType,
+    pub devnum: (u32, u32),
+    pub counters: DeviceCounters,
+}
+
+/// A device granted permission to.
+#[derive(Debug, Clone)]
+pub struct DevicePermission {
+    pub ty: DeviceType,
+    pub devnum: (u32, u32),
+}
+
+impl DeviceCounters {
+    fn add(&mut self, other: &DeviceCounters) {
+        self.allowed.add(&other.allowed);
+        self.denied.add(&other.denied);
+    }
+}
+
+impl std::fmt::Display for DeviceCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "allowed r/w/m {}/{}/{}, denied r/w/m {}/{}/{}",
+            self.allowed.read,
+            self.allowed.write,
+            self.allowed.mknod,
+            self.denied.read,
+            self.denied.write,
+            self.denied.mknod
+        )
+    }
+}
+
+// This needs to match the BPF program.
+#[repr(C)]
+#[derive(FromBytes, Immutable, KnownLayout)]
+struct RawDenial {
+    device_type: u32,
+    major: u32,
+    minor: u32,
+    access: u32,
+    pid: u32,
+    comm: [u8; 16],
+}
+
+/// A device access denied by the filter.
+#[derive(Debug, Clone)]
+pub struct Denial {
+    pub ty: DeviceType,
+    pub devnum: (u32, u32),
+    pub access: Access,
+    pub pid: u32,
+    pub comm: String,
+}
+
+/// Stream of accesses denied by the filter.
+pub struct DenialMonitor {
+    ring: AsyncFd<RingBuf<MapData>>,
+}
+
+impl DenialMonitor {
+    pub async fn next(&mut self) -> Result<Denial> {
+        loop {
+            let mut guard = self.ring.readable_mut().await?;
+            while let Some(item) = guard.get_inner_mut().next() {
+                let Ok(raw) = RawDenial::read_from_bytes(&item) else {
+                    log::warn!("Malformed denial record of {} bytes", item.len());
+                    continue;
+                };
+                let ty = match raw.device_type {
+                    1 => DeviceType::Block,
+                    2 => DeviceType::Character,
+                    _ => continue,
+                };
+                let comm_len = raw.comm.iter().position(|&c| c == 0).unwrap_or(16);
+                return Ok(Denial {
+                    ty,
+                    devnum: (raw.major, raw.minor),
+                    access: Access::from_bits_truncate(raw.access),
+                    pid: raw.pid,
+                    comm: String::from_utf8_lossy(&raw.comm[..comm_len]).into_owned(),
+                });
+            }
+            guard.clear_ready();
+        }
+    }
+}
+
 pub struct DeviceAccessController {
     map: HashMap<MapData, Device, u32>,
-    pin: PathBuf,
+    range_map: HashMap<MapData, DeviceMajor, MinorRanges>,
+    owners: HashMap<MapData, Device, DeviceOwners>,
+    denials: Option<RingBuf<MapData>>,
+    counters: PerCpuHashMap<MapData, Device, DeviceCounters>,
+    /// Content of `range_map`.
+    ranges: BTreeMap<DeviceMajor, Vec<(RangeInclusive<u32>, Access)>>,
+    /// Map entries derived from the container's own device rules.
+    rules: BTreeMap<Device, Access>,
+    pins: Vec<PathBuf>,
 }
 
 impl Drop for DeviceAccessController {
     fn drop(&mut self) {
-        let _ = std::fs::remove_file(&self.pin);
+        for pin in &self.pins {
+            let _ = std::fs::remove_file(pin);
+        }
     }
 }
 
+/// Prefix of paths in bpffs that the filter of the cgroup is pinned to.
+fn pin_prefix(cgroup: &Path) -> Result<String> {
+    // cgroup is of form "/sys/fs/cgroup/system.slice/xxx-yyy.scope", and we can use
+    // the last part as unique identifier.
+    let id = cgroup
+        .file_name()
+        .and_then(OsStr::to_str)
+        .context("Invalid cgroup path")?
+        .trim_end_matches(".scope");
+    Ok(format!("/sys/fs/bpf/{id}"))
+}
+
+// Objects pinned to bpffs, so that the filter survives container-hotplug exiting and can be
+// picked up again.
+const PIN_PROGRAM: &str = "device-filter";
+const PIN_LINK: &str = "device-link";
+const PIN_PERM: &str = "device-perm";
+const PIN_RANGE: &str = "device-range";
+const PIN_DENIALS: &str = "device-denials";
+const PIN_COUNTERS: &str = "device-counters";
+const PIN_OWNER: &str = "device-owner";
+
+/// Objects pinned by device filters, keyed by the name of the cgroup they were created for.
+pub fn pinned_filters() -> Result<BTreeMap<String, Vec<PathBuf>>> {
+    let mut filters = BTreeMap::<_, Vec<_>>::new();
+    for entry in std::fs::read_dir("/sys/fs/bpf")? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+            continue;
+        };
+        let id = [
+            PIN_PROGRAM,
+            PIN_LINK,
+            PIN_PERM,
+            PIN_RANGE,
+            PIN_DENIALS,
+            PIN_COUNTERS,
+            PIN_OWNER,
+        ]
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix)?.strip_suffix('-'));
+        if let Some(id) = id {
+            filters.entry(id.to_owned()).or_default().push(entry.path());
+        }
+    }
+    Ok(filters)
+}
+
+fn load_filter() -> Result<aya::Ebpf> {
+    Ok(aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/cgroup_device_filter/target/bpfel-unknown-none/release/cgroup_device_filter"
+    )))?)
+}
+
+/// Replace a pin atomically, so that it always refers to either the old or the new object.
+fn repin(path: &Path, pin: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".new");
+    let _ = std::fs::remove_file(&tmp);
+    pin(Path::new(&tmp))?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("Cannot replace pin {}", path.display()))?;
+    Ok(())
+}
+
+/// Replace the program attached by a previous instance with ours, if they differ.
+///
+/// The new program is given copies of the existing maps and swapped in atomically, so device
+/// access is uninterrupted. Maps are repinned before the swap, and the link is queried for the
+/// attached program rather than trusting the program pin, so that an upgrade interrupted at any
+/// point can be completed by running it again.
+fn upgrade(prefix: &str) -> Result<()> {
+    let path = |name: &str| PathBuf::from(format!("{prefix}-{name}"));
+    let link = crate::util::bpf::obj_get(&path(PIN_LINK))?;
+    let old_program = crate::util::bpf::link_prog(&link)?;
+
+    let mut bpf = load_filter()?;
+    let mut take_map = |name: &str| -> Result<aya::maps::Map> {
+        bpf.take_map(name)
+            .with_context(|| format!("cannot find {name} map"))
+    };
+    let mut map: HashMap<_, Device, u32> = take_map("DEVICE_PERM")?.try_into()?;
+    let mut range_map: HashMap<_, DeviceMajor, MinorRanges> =
+        take_map("DEVICE_RANGE")?.try_into()?;
+    let denials = take_map("DENIALS")?;
+    let mut counters: PerCpuHashMap<_, Device, DeviceCounters> =
+        take_map("DEVICE_COUNTERS")?.try_into()?;
+    let mut owners: HashMap<_, Device, DeviceOwners> = take_map("DEVICE_OWNER")?.try_into()?;
+
+    let program: &mut CgroupDevice = bpf
+        .program_mut("check_device")
+        .context("cannot find check_device program")?
+        .try_into()?;
+    program.load()?;
+    if crate::util::bpf::prog_tag(program.fd()?.as_fd())?
+        == crate::util::bpf::prog_tag(&old_program)?
+    {
+        return Ok(());
+    }
+
+    // Maps that did not exist in the previous version start out empty.
+    let open = |name: &str| -> Result<Option<MapData>> {
+        match path(name).exists() {
+            true => Ok(Some(MapData::from_pin(path(name))?)),
+            false => Ok(None),
+        }
+    };
+    let old_map: HashMap<_, Device, u32> =
+        aya::maps::Map::HashMap(open(PIN_PERM)?.context("Device filter has no permission map")?)
+            .try_into()?;
+    for entry in old_map.iter() {
+        let (device, access) = entry?;
+        map.insert(device, access, 0)?;
+    }
+    if let Some(old_range_map) = open(PIN_RANGE)? {
+        let old_range_map: HashMap<_, DeviceMajor, MinorRanges> =
+            aya::maps::Map::HashMap(old_range_map).try_into()?;
+        for entry in old_range_map.iter() {
+            let (key, value) = entry?;
+            range_map.insert(key, value, 0)?;
+        }
+    }
+    if let Some(old_counters) = open(PIN_COUNTERS)? {
+        let old_counters: PerCpuHashMap<_, Device, DeviceCounters> =
+            aya::maps::Map::PerCpuLruHashMap(old_counters).try_into()?;
+        for entry in old_counters.iter() {
+            let (device, values) = entry?;
+            counters.insert(device, values, 0)?;
+        }
+    }
+
+    if let Some(old_owners) = open(PIN_OWNER)? {
+        let old_owners: HashMap<_, Device, DeviceOwners> =
+            aya::maps::Map::HashMap(old_owners).try_into()?;
+        for entry in old_owners.iter() {
+            let (device, value) = entry?;
+            owners.insert(device, value, 0)?;
+        }
+    }
+
+    repin(&path(PIN_PERM), |tmp| Ok(map.pin(tmp)?))?;
+    repin(&path(PIN_RANGE), |tmp| Ok(range_map.pin(tmp)?))?;
+    repin(&path(PIN_DENIALS), |tmp| Ok(denials.pin(tmp)?))?;
+    repin(&path(PIN_COUNTERS), |tmp| Ok(counters.pin(tmp)?))?;
+    repin(&path(PIN_OWNER), |tmp| Ok(owners.pin(tmp)?))?;
+
+    crate::util::bpf::link_update(link.as_fd(), program.fd()?.as_fd(), old_program.as_fd())?;
+    repin(&path(PIN_PROGRAM), |tmp| Ok(program.pin(tmp)?))?;
+
+    log::info!("Upgraded device filter {prefix}");
+    Ok(())
+}
+
 impl DeviceAccessController {
-    pub fn new(cgroup: &Path) -> Result<Self> {
-        // cgroup is of form "/sys/fs/cgroup/system.slice/xxx-yyy.scope", and we can use
-        // the last part as unique identifier.
-        let id = cgroup
-            .file_name()
-            .and_then(OsStr::to_str)
-            .context("Invalid cgroup path")?
-            .trim_end_matches(".scope");
+    /// Take over device filtering of the cgroup.
+    ///
+    /// `rules` are the rules that the container is created with, which will be enforced in
+    /// addition to devices that are hot-plugged. In `DeviceFilter::Cooperate` mode, the runtime's
+    /// programs are kept attached instead, see `cooperative_rules` for how rules are handled.
+    pub fn new(cgroup: &Path, rules: &[DeviceRule], mode: DeviceFilter) -> Result<Self> {
+        let prefix = pin_prefix(cgroup)?;
+        let pin = |name: &str| -> Result<PathBuf> {
+            let path = PathBuf::from(format!("{prefix}-{name}"));
+            let _ = std::fs::remove_file(&path);
+            Ok(path)
+        };
 
         // We want to take control of the device cgroup filtering from docker. To do this, we attach our own
-        // filter program and detach the one by docker.
+        // filter program and detach the one by docker, unless we are asked to cooperate with it.
         let cgroup_fd = File::open(cgroup)?;
 
-        let mut bpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/cgroup_device_filter/target/bpfel-unknown-none/release/cgroup_device_filter"
-        )))?;
+        let mut bpf = load_filter()?;
+
+        let mut pins = Vec::new();
+        let mut take_map = |name: &str, pin_name: &str| -> Result<aya::maps::Map> {
+            let map = bpf
+                .take_map(name)
+                .with_context(|| format!("cannot find {name} map"))?;
+            let path = pin(pin_name)?;
+            map.pin(&path)?;
+            pins.push(path);
+            Ok(map)
+        };
+
+        // Populate the map before attaching, so that there is no window where statically
+        // configured devices are inaccessible.
+        let mut map: HashMap<_, Device, u32> = take_map("DEVICE_PERM", PIN_PERM)?.try_into()?;
+        let rules = match mode {
+            DeviceFilter::Replace => compile_rules(rules),
+            DeviceFilter::Cooperate => cooperative_rules(compile_rules(rules)),
+        };
+        for (device, access) in &rules {
+            map.insert(device, access.bits(), 0)?;
+        }
+        let range_map = take_map("DEVICE_RANGE", PIN_RANGE)?.try_into()?;
+        let denials = take_map("DENIALS", PIN_DENIALS)?.try_into()?;
+        let counters = take_map("DEVICE_COUNTERS", PIN_COUNTERS)?.try_into()?;
+        let owners = take_map("DEVICE_OWNER", PIN_OWNER)?.try_into()?;
 
         let program: &mut CgroupDevice = bpf
             .program_mut("check_device")
@@ -75,49 +586,538 @@ impl DeviceAccessController {
         // Wrap this inside `ManuallyDrop` to prevent accidental detaching.
         let existing_programs = ManuallyDrop::new(CgroupDevice::query(&cgroup_fd)?);
 
-        let link_id = program.attach(&cgroup_fd, CgroupAttachMode::Single)?;
-
-        // Forget the link so it won't be detached on drop.
-        let link = program.take_link(link_id);
-        std::mem::forget(link);
+        // Links to cgroups always allow multiple programs, so both modes attach the same way and
+        // only differ in whether existing programs are detached.
+        let link =
+            crate::util::bpf::link_create_cgroup_device(program.fd()?.as_fd(), cgroup_fd.as_fd())?;
 
-        // Pin the program so that if container-hotplug accidentally exits, the filter won't be removed from the docker
-        // container.
-        let pin: PathBuf = format!("/sys/fs/bpf/{id}-device-filter").into();
-        let _ = std::fs::remove_file(&pin);
-        program.pin(&pin)?;
+        // Pin the link so that if container-hotplug accidentally exits, the filter won't be removed from the docker
+        // container. The program is pinned as well so it can be identified.
+        let path = pin(PIN_LINK)?;
+        crate::util::bpf::pin(&link, &path)?;
+        pins.push(path);
+        let path = pin(PIN_PROGRAM)?;
+        program.pin(&path)?;
+        pins.push(path);
 
         // Now our new filter is attached, detach all docker filters.
-        for existing_program in ManuallyDrop::into_inner(existing_programs) {
-            existing_program.detach()?;
+        if mode == DeviceFilter::Replace {
+            for existing_program in ManuallyDrop::into_inner(existing_programs) {
+                existing_program.detach()?;
+            }
         }
 
-        let map: HashMap<_, Device, u32> = bpf
-            .take_map("DEVICE_PERM")
-            .context("cannot find DEVICE_PERM map")?
-            .try_into()?;
+        Ok(Self {
+            map,
+            range_map,
+            owners,
+            ranges: BTreeMap::new(),
+            denials: Some(denials),
+            counters,
+            rules,
+            pins,
+        })
+    }
+
+    /// Pick up device filtering of the cgroup from a previous instance.
+    ///
+    /// If the previous instance attached a different version of the filter, it is upgraded in
+    /// place. `rules` and `mode` must be the same as when the filter was created. Filters of
+    /// releases that did not pin their link cannot be picked up, as the link is not reachable.
+    pub fn from_pin(cgroup: &Path, rules: &[DeviceRule], mode: DeviceFilter) -> Result<Self> {
+        let prefix = pin_prefix(cgroup)?;
+        ensure!(
+            Path::new(&format!("{prefix}-{PIN_LINK}")).exists(),
+            "Device filter is not attached to {}",
+            cgroup.display()
+        );
+        upgrade(&prefix).context("Cannot upgrade device filter")?;
 
-        Ok(Self { map, pin })
+        let paths: Vec<PathBuf> = [
+            PIN_PERM,
+            PIN_RANGE,
+            PIN_DENIALS,
+            PIN_COUNTERS,
+            PIN_LINK,
+            PIN_PROGRAM,
+            PIN_OWNER,
+        ]
+        .iter()
+        .map(|name| format!("{prefix}-{name}").into())
+        .collect();
+        let open = |path: &Path| {
+            MapData::from_pin(path)
+                .with_context(|| format!("Cannot open pinned map {}", path.display()))
+        };
+
+        let map: HashMap<_, Device, u32> = aya::maps::Map::HashMap(open(&paths[0])?).try_into()?;
+        let range_map: HashMap<_, DeviceMajor, MinorRanges> =
+            aya::maps::Map::HashMap(open(&paths[1])?).try_into()?;
+        let denials = aya::maps::Map::RingBuf(open(&paths[2])?).try_into()?;
+        let counters = aya::maps::Map::PerCpuLruHashMap(open(&paths[3])?).try_into()?;
+        let owners = aya::maps::Map::HashMap(open(&paths[6])?).try_into()?;
+
+        let mut ranges = BTreeMap::<_, Vec<_>>::new();
+        for entry in range_map.iter() {
+            let (key, value) = entry?;
+            ranges.insert(
+                key,
+                value
+                    .ranges
+                    .iter()
+                    .take(value.count as usize)
+                    .map(|range| {
+                        (
+                            range.start..=range.end,
+                            Access::from_bits_truncate(range.access),
+                        )
+                    })
+                    .collect(),
+            );
+        }
+
+        let rules = match mode {
+            DeviceFilter::Replace => compile_rules(rules),
+            DeviceFilter::Cooperate => cooperative_rules(compile_rules(rules)),
+        };
+
+        Ok(Self {
+            map,
+            range_map,
+            owners,
+            ranges,
+            denials: Some(denials),
+            counters,
+            rules,
+            pins: paths,
+        })
+    }
+
+    /// Take the stream of accesses denied by the filter. This can only be taken once.
+    pub fn take_denials(&mut self) -> Result<Option<DenialMonitor>> {
+        let Some(ring) = self.denials.take() else {
+            return Ok(None);
+        };
+        Ok(Some(DenialMonitor {
+            ring: AsyncFd::new(ring)?,
+        }))
     }
 
-    /// Set the permission for a specific device.
-    pub fn set_permission(
+    /// Access check counters of a specific device, summed over all CPUs.
+    pub fn counters(&self, ty: DeviceType, major: u32, minor: u32) -> Result<DeviceCounters> {
+        let device = Device {
+            device_type: ty as u32,
+            major,
+            minor,
+        };
+        let mut sum = DeviceCounters::default();
+        match self.counters.get(&device, 0) {
+            Ok(values) => values.iter().for_each(|counters| sum.add(counters)),
+            Err(MapError::KeyNotFound) => (),
+            Err(err) => Err(err)?,
+        }
+        Ok(sum)
+    }
+
+    /// Reset access check counters of a device, e.g. because its device number is reused by a
+    /// different device.
+    pub fn reset_counters(&mut self, ty: DeviceType, major: u32, minor: u32) -> Result<()> {
+        let device = Device {
+            device_type: ty as u32,
+            major,
+            minor,
+        };
+        match self.counters.get(&device, 0) {
+            Ok(_) => self.counters.remove(&device)?,
+            Err(MapError::KeyNotFound) => (),
+            Err(err) => Err(err)?,
+        }
+        Ok(())
+    }
+
+    /// Access check counters of all devices that have been checked, summed over all CPUs.
+    ///
+    /// Devices that are least recently checked may be missing if too many devices are checked.
+    pub fn all_counters(&self) -> Result<Vec<DeviceUsage>> {
+        let mut result = Vec::new();
+        for entry in self.counters.iter() {
+            let (device, values) = entry?;
+            let ty = match device.device_type {
+                1 => DeviceType::Block,
+                2 => DeviceType::Character,
+                _ => continue,
+            };
+            let mut sum = DeviceCounters::default();
+            values.iter().for_each(|counters| sum.add(counters));
+            result.push(DeviceUsage {
+                ty,
+                devnum: (device.major, device.minor),
+                counters: sum,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Permission of a single device as present in the map, regardless of where it comes from.
+    ///
+    /// Devices without an entry of their own have no permission.
+    pub fn permission(&self, ty: DeviceType, major: u32, minor: u32) -> Result<Access> {
+        let device = Device {
+            device_type: ty as u32,
+            major,
+            minor,
+        };
+        match self.map.get(&device, 0) {
+            Ok(access) => Ok(Access::from_bits_truncate(access)),
+            Err(MapError::KeyNotFound) => Ok(Access::empty()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Permissions of single devices that are granted on top of what the container is configured
+    /// with.
+    pub fn granted(&self) -> Result<Vec<DevicePermission>> {
+        let mut result = Vec::new();
+        for entry in self.map.iter() {
+            let (device, access) = entry?;
+            let ty = match device.device_type {
+                1 => DeviceType::Block,
+                2 => DeviceType::Character,
+                _ => continue,
+            };
+            if device.major == WILDCARD || device.minor == WILDCARD {
+                continue;
+            }
+            if self
+                .rules
+                .get(&device)
+                .is_some_and(|rule| rule.bits() == access)
+            {
+                continue;
+            }
+            result.push(DevicePermission {
+                ty,
+                devnum: (device.major, device.minor),
+            });
+        }
+        Ok(result)
+    }
+
+    /// Set the permission for a set of devices.
+    ///
+    /// Permissions of a single device take precedence over those of minor ranges, which in turn
+    /// take precedence over those of a whole major number. Minor ranges of the same major number
+    /// cannot overlap, unless they are identical in which case the permission is replaced.
+    ///
+    /// Setting an empty permission removes the entry.
+    pub fn set_permission(&mut self, ty: DeviceType, set: DeviceSet, access: Access) -> Result<()> {
+        let (major, minor) = match set {
+            DeviceSet::Device(major, minor) => (major, minor),
+            DeviceSet::Major(major) => (major, WILDCARD),
+            DeviceSet::Range(major, minors) => return self.set_range(ty, major, minors, access),
+        };
+
+        let device = Device {
+            device_type: ty as u32,
+            major,
+            minor,
+        };
+        if let Some(access) = access.is_empty().then(|| self.rules.get(&device)).flatten() {
+            // Fall back to what the container is configured with.
+            self.map.insert(device, access.bits(), 0)?;
+        } else if access.is_empty() {
+            self.map.remove(&device)?;
+        } else {
+            self.map.insert(device, access.bits(), 0)?;
+        }
+        Ok(())
+    }
+
+    /// Restrict read and write access of a device to the given owners, in addition to its
+    /// permission. A process is an owner if its UID or GID is listed.
+    ///
+    /// IDs are as seen from the initial user namespace. Setting no owners lifts the restriction.
+    pub fn set_owners(
         &mut self,
         ty: DeviceType,
         major: u32,
         minor: u32,
-        access: Access,
+        uids: &[u32],
+        gids: &[u32],
     ) -> Result<()> {
         let device = Device {
             device_type: ty as u32,
             major,
             minor,
         };
-        if access.is_empty() {
-            self.map.remove(&device)?;
-        } else {
-            self.map.insert(device, access.bits(), 0)?;
+        if uids.is_empty() && gids.is_empty() {
+            match self.owners.get(&device, 0) {
+                Ok(_) => self.owners.remove(&device)?,
+                Err(MapError::KeyNotFound) => (),
+                Err(err) => Err(err)?,
+            }
+            return Ok(());
         }
+
+        ensure!(
+            uids.len() <= MAX_OWNERS && gids.len() <= MAX_OWNERS,
+            "Too many owners for device {major}:{minor}"
+        );
+        let mut value = DeviceOwners {
+            uid_count: uids.len() as u32,
+            gid_count: gids.len() as u32,
+            ..Default::default()
+        };
+        value.uids[..uids.len()].copy_from_slice(uids);
+        value.gids[..gids.len()].copy_from_slice(gids);
+        self.owners.insert(device, value, 0)?;
+        Ok(())
+    }
+
+    fn set_range(
+        &mut self,
+        ty: DeviceType,
+        major: u32,
+        minors: RangeInclusive<u32>,
+        access: Access,
+    ) -> Result<()> {
+        ensure!(!minors.is_empty(), "Minor range {minors:?} is empty");
+        ensure!(
+            *minors.end() != WILDCARD,
+            "Minor range {minors:?} is out of bounds"
+        );
+
+        let key = DeviceMajor {
+            device_type: ty as u32,
+            major,
+        };
+        let mut ranges = self.ranges.get(&key).cloned().unwrap_or_default();
+        ranges.retain(|(range, _)| *range != minors);
+        if !access.is_empty() {
+            if let Some((range, _)) = ranges
+                .iter()
+                .find(|(range, _)| range.start() <= minors.end() && minors.start() <= range.end())
+            {
+                bail!("Minor range {minors:?} overlaps with {range:?} of major {major}");
+            }
+            ensure!(
+                ranges.len() < MAX_RANGES,
+                "Too many minor ranges for major {major}"
+            );
+            ranges.push((minors, access));
+        }
+
+        if ranges.is_empty() {
+            self.range_map.remove(&key)?;
+            self.ranges.remove(&key);
+            return Ok(());
+        }
+
+        let mut value = MinorRanges {
+            count: ranges.len() as u32,
+            ranges: [MinorRange::default(); MAX_RANGES],
+        };
+        for (slot, (range, access)) in value.ranges.iter_mut().zip(&ranges) {
+            *slot = MinorRange {
+                start: *range.start(),
+                end: *range.end(),
+                access: access.bits(),
+            };
+        }
+        self.range_map.insert(key, value, 0)?;
+        self.ranges.insert(key, ranges);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    use rustix::fs::{FileType, Mode, OFlags};
+    use rustix::io::Errno;
+
+    use super::*;
+
+    /// Rules as a runtime would generate them: deny everything, then allow a device and a class.
+    fn rules() -> Vec<DeviceRule> {
+        let rule = |allow, major, minor, access| DeviceRule {
+            allow,
+            ty: if allow {
+                Some(DeviceType::Character)
+            } else {
+                None
+            },
+            major,
+            minor,
+            access,
+        };
+        vec![
+            rule(false, None, None, Access::all()),
+            rule(true, Some(242), Some(0), Access::all()),
+            rule(true, Some(240), None, Access::READ | Access::WRITE),
+        ]
+    }
+
+    fn key(ty: DeviceType, major: u32, minor: u32) -> Device {
+        Device {
+            device_type: ty as u32,
+            major,
+            minor,
+        }
+    }
+
+    #[test]
+    fn cooperative_rules_leave_unlisted_devices_to_runtime() {
+        let map = cooperative_rules(compile_rules(&rules()));
+        for ty in [DeviceType::Block, DeviceType::Character] {
+            assert_eq!(
+                map[&key(ty, WILDCARD, WILDCARD)].bits(),
+                Access::all().bits()
+            );
+        }
+        assert!(map[&key(DeviceType::Character, 240, WILDCARD)].is_empty());
+        assert_eq!(
+            map[&key(DeviceType::Character, 242, 0)].bits(),
+            Access::all().bits()
+        );
+    }
+
+    /// A cgroup created in the cgroup2 hierarchy for the duration of a test, with a directory
+    /// for device nodes to test access with.
+    struct TestCgroup {
+        path: PathBuf,
+        nodes: PathBuf,
+    }
+
+    impl TestCgroup {
+        /// Returns `None` if the environment does not support device filters, e.g. when not
+        /// running as root or bpffs is not mounted.
+        fn new(name: &str) -> Option<Self> {
+            let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+            let mounts: Vec<(&str, &str)> = mountinfo
+                .lines()
+                .filter_map(|line| {
+                    let (mount, fs) = line.split_once(" - ")?;
+                    Some((mount.split(' ').nth(4)?, fs.split(' ').next()?))
+                })
+                .collect();
+            if !mounts.contains(&("/sys/fs/bpf", "bpf")) {
+                return None;
+            }
+            // Loading requires CAP_BPF.
+            load_filter().ok()?;
+            let (root, _) = mounts.iter().find(|(_, fs)| *fs == "cgroup2")?;
+
+            let name = format!("container-hotplug-test-{}-{name}", std::process::id());
+            let path = Path::new(root).join(&name);
+            std::fs::create_dir(&path).ok()?;
+            let nodes = std::env::temp_dir().join(&name);
+            let cgroup = Self { path, nodes };
+            std::fs::create_dir(&cgroup.nodes).ok()?;
+            // Creating device nodes requires root, and nodes may not be usable on the filesystem.
+            cgroup.node(242, 0).ok()?;
+            Some(cgroup)
+        }
+
+        fn node(&self, major: u32, minor: u32) -> Result<CString> {
+            let path = self.nodes.join(format!("{major}-{minor}"));
+            if !path.exists() {
+                rustix::fs::mknodat(
+                    rustix::fs::CWD,
+                    &path,
+                    FileType::CharacterDevice,
+                    Mode::from(0o600),
+                    rustix::fs::makedev(major, minor),
+                )?;
+            }
+            Ok(CString::new(path.as_os_str().as_bytes())?)
+        }
+
+        /// Whether a process in the cgroup may open a character device.
+        ///
+        /// No driver is registered for the device numbers used, so opening fails either way,
+        /// but the device filter is consulted first.
+        fn allows(&self, major: u32, minor: u32) -> bool {
+            let node = self.node(major, minor).unwrap();
+            let procs =
+                CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes()).unwrap();
+            let mut command = Command::new("true");
+            // SAFETY: Only async-signal-safe system calls are made in the child.
+            unsafe {
+                command.pre_exec(move || {
+                    let procs = rustix::fs::open(procs.as_c_str(), OFlags::WRONLY, Mode::empty())?;
+                    rustix::io::write(&procs, b"0")?;
+                    match rustix::fs::open(node.as_c_str(), OFlags::RDONLY, Mode::empty()) {
+                        Err(Errno::PERM) => Err(Errno::PERM.into()),
+                        _ => Ok(()),
+                    }
+                })
+            };
+            match command.status() {
+                Ok(_) => true,
+                Err(err) if err.raw_os_error() == Some(Errno::PERM.raw_os_error()) => false,
+                Err(err) => panic!("Cannot run process in cgroup: {err}"),
+            }
+        }
+    }
+
+    impl Drop for TestCgroup {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.nodes);
+            let _ = std::fs::remove_dir(&self.path);
+        }
+    }
+
+    #[test]
+    fn replace_enforces_rules() {
+        let Some(cgroup) = TestCgroup::new("replace") else {
+            eprintln!("Device filters are not supported, skipping");
+            return;
+        };
+        let mut controller =
+            DeviceAccessController::new(&cgroup.path, &rules(), DeviceFilter::Replace).unwrap();
+
+        assert!(cgroup.allows(242, 0));
+        assert!(cgroup.allows(240, 1));
+        assert!(!cgroup.allows(241, 0));
+
+        controller
+            .set_permission(
+                DeviceType::Character,
+                DeviceSet::Device(241, 0),
+                Access::READ,
+            )
+            .unwrap();
+        assert!(cgroup.allows(241, 0));
+    }
+
+    #[test]
+    fn cooperate_leaves_unlisted_devices_to_runtime() {
+        let Some(cgroup) = TestCgroup::new("cooperate") else {
+            eprintln!("Device filters are not supported, skipping");
+            return;
+        };
+        let mut controller =
+            DeviceAccessController::new(&cgroup.path, &rules(), DeviceFilter::Cooperate).unwrap();
+
+        // Without a runtime program attached, everything the runtime would decide is allowed.
+        assert!(cgroup.allows(242, 0));
+        assert!(cgroup.allows(241, 0));
+        // Device classes allowed by wildcard rules are only accessible once hot-plugged.
+        assert!(!cgroup.allows(240, 1));
+
+        controller
+            .set_permission(
+                DeviceType::Character,
+                DeviceSet::Device(240, 1),
+                Access::READ,
+            )
+            .unwrap();
+        assert!(cgroup.allows(240, 1));
+        assert!(!cgroup.allows(240, 2));
+    }
+}
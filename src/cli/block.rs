@@ -0,0 +1,103 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result, bail, ensure};
+
+use crate::cgroup::{Access, DeviceType};
+use crate::dev::Device;
+
+#[derive(Clone)]
+pub enum BlockDevice {
+    /// A whole disk.
+    Disk,
+    /// Any partition.
+    Partition,
+    /// A partition with the given `ID_PART_ENTRY_NAME`.
+    NamedPartition(String),
+}
+
+/// Access policy for block devices, keyed by their devtype.
+#[derive(Clone)]
+pub struct BlockAccess {
+    device: BlockDevice,
+    /// `None` if the device should not be exposed to the container at all.
+    access: Option<Access>,
+}
+
+impl FromStr for BlockAccess {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((device, access)) = s.split_once('=') else {
+            bail!("Block access format should be `<DEVTYPE>[:<NAME>]=<ACCESS>`, found `{s}`");
+        };
+
+        let device = match device.split_once(':') {
+            None if device == "disk" => BlockDevice::Disk,
+            None if device == "partition" => BlockDevice::Partition,
+            Some(("partition", name)) => {
+                ensure!(
+                    !name.is_empty(),
+                    "Block access partition NAME should not be empty"
+                );
+                BlockDevice::NamedPartition(name.to_owned())
+            }
+            _ => bail!(
+                "Block access DEVTYPE should be one of `disk`, `partition` or `partition:<NAME>`, found `{device}`"
+            ),
+        };
+
+        let access = match access {
+            "rw" => Some(Access::all()),
+            "ro" => Some(Access::MKNOD | Access::READ),
+            "none" => None,
+            _ => {
+                bail!("Block access ACCESS should be one of `rw`, `ro` or `none`, found `{access}`")
+            }
+        };
+
+        Ok(BlockAccess { device, access })
+    }
+}
+
+impl BlockAccess {
+    /// Determine the access to grant to a device.
+    ///
+    /// A named partition rule takes precedence over a generic partition rule. Devices that are not
+    /// covered by any rule (including all non-block devices) get full access.
+    pub fn access(policies: &[BlockAccess], device: &Device) -> Option<Access> {
+        let Some(devnode) = device.devnode() else {
+            return Some(Access::all());
+        };
+        if devnode.ty != DeviceType::Block {
+            return Some(Access::all());
+        }
+
+        let udev = device.udev();
+        let policy = match udev.devtype().and_then(|ty| ty.to_str()) {
+            Some("disk") => policies
+                .iter()
+                .rfind(|policy| matches!(policy.device, BlockDevice::Disk)),
+            Some("partition") => {
+                let name = udev
+                    .property_value("ID_PART_ENTRY_NAME")
+                    .and_then(|name| name.to_str());
+                policies
+                    .iter()
+                    .rfind(|policy| match &policy.device {
+                        BlockDevice::NamedPartition(expected) => Some(expected.as_str()) == name,
+                        _ => false,
+                    })
+                    .or_else(|| {
+                        policies
+                            .iter()
+                            .rfind(|policy| matches!(policy.device, BlockDevice::Partition))
+                    })
+            }
+            _ => None,
+        };
+
+        match policy {
+            Some(policy) => policy.access,
+            None => Some(Access::all()),
+        }
+    }
+}
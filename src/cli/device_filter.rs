@@ -0,0 +1,24 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result, bail};
+
+/// How the device filter interacts with device cgroup programs installed by the runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeviceFilter {
+    /// Detach the runtime's programs and enforce their rules in our own program.
+    #[default]
+    Replace,
+    /// Attach alongside the runtime's programs, only narrowing what they allow.
+    Cooperate,
+}
+
+impl FromStr for DeviceFilter {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(DeviceFilter::Replace),
+            "cooperate" => Ok(DeviceFilter::Cooperate),
+            _ => bail!("Device filter mode should be one of `replace` or `cooperate`, found `{s}`"),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Error, Result, bail};
+
+use crate::cgroup::{Access, DeviceSet, DeviceType};
+
+/// Devices that the container is granted access to regardless of hot-plugging.
+#[derive(Clone)]
+pub struct DeviceCgroupRule {
+    pub ty: DeviceType,
+    pub set: DeviceSet,
+    pub access: Access,
+}
+
+impl FromStr for DeviceCgroupRule {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [ty, number, access] = s.split_whitespace().collect::<Vec<_>>()[..] else {
+            bail!(
+                "Device cgroup rule format should be `<TYPE> <MAJOR>:<MINOR> <ACCESS>`, found `{s}`"
+            );
+        };
+
+        let ty = match ty {
+            "b" => DeviceType::Block,
+            "c" => DeviceType::Character,
+            _ => bail!("Device cgroup rule TYPE should be one of `b` or `c`, found `{ty}`"),
+        };
+
+        let Some((major, minor)) = number.split_once(':') else {
+            bail!("Device cgroup rule number should be `<MAJOR>:<MINOR>`, found `{number}`");
+        };
+        let major = major
+            .parse()
+            .context("Device cgroup rule MAJOR should be a number")?;
+        let set = if minor == "*" {
+            DeviceSet::Major(major)
+        } else if let Some((start, end)) = minor.split_once('-') {
+            let start = start
+                .parse()
+                .context("Device cgroup rule MINOR range start should be a number")?;
+            let end = end
+                .parse()
+                .context("Device cgroup rule MINOR range end should be a number")?;
+            DeviceSet::Range(major, start..=end)
+        } else {
+            let minor = minor.parse().context(
+                "Device cgroup rule MINOR should be a number, a range `<START>-<END>` or `*`",
+            )?;
+            DeviceSet::Device(major, minor)
+        };
+
+        let mut access_flags = Access::empty();
+        for c in access.chars() {
+            access_flags |= match c {
+                'r' => Access::READ,
+                'w' => Access::WRITE,
+                'm' => Access::MKNOD,
+                _ => bail!(
+                    "Device cgroup rule ACCESS should consist of `r`, `w` and `m`, found `{access}`"
+                ),
+            };
+        }
+
+        Ok(DeviceCgroupRule {
+            ty,
+            set,
+            access: access_flags,
+        })
+    }
+}
@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, ensure};
+
+use super::symlink::SymlinkDevice;
+use crate::dev::Device;
+
+/// A set of devices that are attached all together or not at all.
+///
+/// Members are matched per physical USB device, so that each device carrying the members forms
+/// its own instance of the group.
+#[derive(Clone)]
+pub struct DeviceGroup {
+    pub name: String,
+    members: Vec<SymlinkDevice>,
+}
+
+impl DeviceGroup {
+    pub fn new(name: String, members: Vec<SymlinkDevice>) -> Result<Self> {
+        ensure!(!members.is_empty(), "Device group `{name}` has no members");
+        Ok(Self { name, members })
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        self.members.iter().any(|member| member.matches(device))
+    }
+
+    /// The instance of a group that a member belongs to, which is the syspath of the USB device
+    /// it is part of.
+    pub fn instance(device: &Device) -> PathBuf {
+        let udev = device.udev();
+        if udev.devtype().is_some_and(|ty| ty == "usb_device") {
+            return device.syspath().to_owned();
+        }
+        match udev.parent_with_subsystem_devtype("usb", "usb_device") {
+            Ok(Some(parent)) => parent.syspath().to_owned(),
+            _ => device.syspath().to_owned(),
+        }
+    }
+
+    /// Whether each member is matched by one of the devices.
+    pub fn is_complete<'a>(&self, devices: impl Iterator<Item = &'a Device> + Clone) -> bool {
+        self.members
+            .iter()
+            .all(|member| devices.clone().any(|device| member.matches(device)))
+    }
+}
@@ -0,0 +1,43 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Error, Result, bail, ensure};
+
+/// IO limit to apply to hot-plugged disks via the io cgroup controller.
+#[derive(Clone)]
+pub enum IoLimit {
+    /// A key of `io.max`, i.e. `rbps`, `wbps`, `riops` or `wiops`, and its value.
+    Max(String, u64),
+    /// The `io.weight` of the device.
+    Weight(u16),
+}
+
+impl FromStr for IoLimit {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((key, value)) = s.split_once('=') else {
+            bail!("IO limit format should be `<KEY>=<VALUE>`, found `{s}`");
+        };
+
+        match key {
+            "rbps" | "wbps" | "riops" | "wiops" => {
+                let value = value
+                    .parse()
+                    .with_context(|| format!("IO limit `{key}` should be a number"))?;
+                Ok(IoLimit::Max(key.to_owned(), value))
+            }
+            "weight" => {
+                let weight = value
+                    .parse()
+                    .context("IO limit `weight` should be a number")?;
+                ensure!(
+                    (1..=10000).contains(&weight),
+                    "IO limit `weight` should be between 1 and 10000, found `{weight}`"
+                );
+                Ok(IoLimit::Weight(weight))
+            }
+            _ => bail!(
+                "IO limit KEY should be one of `rbps`, `wbps`, `riops`, `wiops` or `weight`, found `{key}`"
+            ),
+        }
+    }
+}
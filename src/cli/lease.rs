@@ -0,0 +1,47 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result, ensure};
+
+/// How long devices are leased to the container for.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lease {
+    /// `None` if devices are not leased, i.e. they are kept until unplugged.
+    pub duration: Option<Duration>,
+    /// How long before expiry the container is warned.
+    pub warning: Option<Duration>,
+}
+
+impl FromStr for Lease {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (duration, warning) = match s.split_once(',') {
+            Some((duration, warning)) => {
+                let warning = warning.strip_prefix("warn=").with_context(|| {
+                    format!("Lease format should be `<DURATION>[,warn=<DURATION>]`, found `{s}`")
+                })?;
+                (duration, Some(warning))
+            }
+            None => (s, None),
+        };
+
+        let duration = humantime::parse_duration(duration)
+            .with_context(|| format!("Lease DURATION is invalid, found `{duration}`"))?;
+        let warning = warning
+            .map(|warning| {
+                humantime::parse_duration(warning).with_context(|| {
+                    format!("Lease warning DURATION is invalid, found `{warning}`")
+                })
+            })
+            .transpose()?;
+        ensure!(
+            warning.is_none_or(|warning| warning < duration),
+            "Lease warning should be shorter than the lease, found `{s}`"
+        );
+
+        Ok(Lease {
+            duration: Some(duration),
+            warning,
+        })
+    }
+}
@@ -0,0 +1,27 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result, bail};
+
+/// Whether devices plugged after the container is initialized are attached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockAfterInit {
+    /// Attach devices whenever they are plugged.
+    #[default]
+    Off,
+    /// Only devices present at initialization are attached.
+    On,
+    /// Like `On`, but devices present at initialization are attached again when re-plugged.
+    AllowReplug,
+}
+
+impl FromStr for LockAfterInit {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(LockAfterInit::Off),
+            "on" => Ok(LockAfterInit::On),
+            "allow-replug" => Ok(LockAfterInit::AllowReplug),
+            _ => bail!("Lock mode should be one of `off`, `on` or `allow-replug`, found `{s}`"),
+        }
+    }
+}
@@ -1,5 +1,33 @@
+pub mod block;
 pub mod device;
+pub mod device_filter;
+pub mod device_rule;
+pub mod group;
+pub mod io;
+pub mod lease;
+pub mod lock;
+pub mod mount;
+pub mod netif;
+pub mod owner;
+pub mod reconcile;
+pub mod required;
+pub mod root_unplug;
+pub mod settle;
 pub mod symlink;
 
+pub use block::BlockAccess;
 pub use device::DeviceRef;
+pub use device_filter::DeviceFilter;
+pub use device_rule::DeviceCgroupRule;
+pub use group::DeviceGroup;
+pub use io::IoLimit;
+pub use lease::Lease;
+pub use lock::LockAfterInit;
+pub use mount::Mount;
+pub use netif::NetInterface;
+pub use owner::DeviceOwner;
+pub use reconcile::ReconcileInterval;
+pub use required::{Required, RequiredTimeout};
+pub use root_unplug::RootUnplug;
+pub use settle::Settle;
 pub use symlink::Symlink;
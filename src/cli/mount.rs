@@ -0,0 +1,143 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Error, Result, bail, ensure};
+use rustix::mount::MountAttrFlags;
+
+use crate::cgroup::DeviceType;
+use crate::dev::Device;
+
+/// Mount policy for filesystems on hot-plugged block devices.
+#[derive(Clone)]
+pub struct Mount {
+    fstype: String,
+    attributes: MountAttrFlags,
+    parameters: Vec<(String, Option<String>)>,
+    path: String,
+}
+
+impl FromStr for Mount {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((spec, path)) = s.rsplit_once('=') else {
+            bail!("Mount format should be `<FSTYPE>[:<OPTION>[+<OPTION>]*]=<PATH>`, found `{s}`");
+        };
+
+        ensure!(
+            path.starts_with('/') && !path.ends_with('/'),
+            "Mount PATH should be an absolute directory path, found `{path}`."
+        );
+        let mut rest = path;
+        while let Some((_, after)) = rest.split_once('{') {
+            let Some((placeholder, after)) = after.split_once('}') else {
+                bail!("Mount PATH has an unterminated placeholder, found `{path}`");
+            };
+            ensure!(
+                matches!(placeholder, "name" | "label" | "uuid"),
+                "Mount PATH placeholder should be one of `{{name}}`, `{{label}}` or `{{uuid}}`, found `{{{placeholder}}}`"
+            );
+            rest = after;
+        }
+
+        let (fstype, options) = spec.split_once(':').unwrap_or((spec, ""));
+        ensure!(
+            !fstype.is_empty()
+                && fstype
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+            "Mount FSTYPE should be a filesystem type, found `{fstype}`"
+        );
+
+        // Hot-plugged media are never trusted with setuid binaries or device nodes.
+        let mut attributes = MountAttrFlags::MOUNT_ATTR_NOSUID | MountAttrFlags::MOUNT_ATTR_NODEV;
+        let mut parameters = Vec::new();
+        for option in options.split('+').filter(|option| !option.is_empty()) {
+            match option {
+                "ro" => {
+                    // Make both the mount and the superblock read-only, so the filesystem driver
+                    // will not e.g. replay journals on the device.
+                    attributes |= MountAttrFlags::MOUNT_ATTR_RDONLY;
+                    parameters.push(("ro".to_owned(), None));
+                }
+                "noexec" => attributes |= MountAttrFlags::MOUNT_ATTR_NOEXEC,
+                "noatime" => attributes |= MountAttrFlags::MOUNT_ATTR_NOATIME,
+                "nodiratime" => attributes |= MountAttrFlags::MOUNT_ATTR_NODIRATIME,
+                "suid" | "dev" => {
+                    bail!("Mount OPTION `{option}` is not allowed for hot-plugged filesystems")
+                }
+                _ => match option.split_once('=') {
+                    Some((key, value)) => parameters.push((key.to_owned(), Some(value.to_owned()))),
+                    None => parameters.push((option.to_owned(), None)),
+                },
+            }
+        }
+
+        Ok(Mount {
+            fstype: fstype.to_owned(),
+            attributes,
+            parameters,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Mount {
+    pub fn fstype(&self) -> &str {
+        &self.fstype
+    }
+
+    pub fn attributes(&self) -> MountAttrFlags {
+        self.attributes
+    }
+
+    /// The same policy but with the filesystem mounted read-only.
+    pub fn read_only(&self) -> Mount {
+        let mut mount = self.clone();
+        if !mount.attributes.contains(MountAttrFlags::MOUNT_ATTR_RDONLY) {
+            mount.attributes |= MountAttrFlags::MOUNT_ATTR_RDONLY;
+            mount.parameters.push(("ro".to_owned(), None));
+        }
+        mount
+    }
+
+    /// Filesystem parameters to be passed to `fsconfig`.
+    pub fn parameters(&self) -> &[(String, Option<String>)] {
+        &self.parameters
+    }
+
+    /// Expand the PATH template for the device.
+    ///
+    /// `{name}`, `{label}` and `{uuid}` are replaced with the kernel name, filesystem label and
+    /// filesystem UUID respectively. Returns `None` if a placeholder cannot be resolved.
+    fn expand(&self, device: &udev::Device) -> Option<PathBuf> {
+        let mut path = String::new();
+        let mut rest = self.path.as_str();
+        while let Some((before, after)) = rest.split_once('{') {
+            let (placeholder, after) = after.split_once('}')?;
+            let value = match placeholder {
+                "name" => device.sysname().to_str()?,
+                "label" => device.property_value("ID_FS_LABEL")?.to_str()?,
+                "uuid" => device.property_value("ID_FS_UUID")?.to_str()?,
+                _ => return None,
+            };
+            if value.is_empty() || value == "." || value == ".." || value.contains('/') {
+                return None;
+            }
+            path.push_str(before);
+            path.push_str(value);
+            rest = after;
+        }
+        path.push_str(rest);
+        Some(path.into())
+    }
+
+    pub fn matches(&self, device: &Device) -> Option<PathBuf> {
+        if device.devnode()?.ty != DeviceType::Block {
+            return None;
+        }
+        if device.udev().property_value("ID_FS_TYPE")?.to_str()? != self.fstype {
+            return None;
+        }
+        self.expand(device.udev())
+    }
+}
@@ -0,0 +1,52 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result, ensure};
+
+use super::symlink::SymlinkDevice;
+use crate::dev::Device;
+
+/// Name to give to a network interface when it is moved into the container.
+#[derive(Clone)]
+pub struct NetInterface {
+    device: SymlinkDevice,
+    name: String,
+}
+
+impl FromStr for NetInterface {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split('=').collect();
+        ensure!(
+            parts.len() == 2,
+            "Network interface format should be `<PREFIX>:<DEVICE>=<NAME>`, found `{s}`"
+        );
+
+        let dev = parts[0];
+        let name = parts[1];
+
+        // Same constraints as `dev_valid_name` in the kernel.
+        ensure!(
+            !name.is_empty()
+                && name.len() < 16
+                && name != "."
+                && name != ".."
+                && !name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()),
+            "Network interface NAME should be a valid interface name, found `{name}`."
+        );
+
+        Ok(NetInterface {
+            device: dev.parse()?,
+            name: name.to_owned(),
+        })
+    }
+}
+
+impl NetInterface {
+    pub fn matches(&self, device: &Device) -> Option<String> {
+        if self.device.matches(device) {
+            Some(self.name.clone())
+        } else {
+            None
+        }
+    }
+}
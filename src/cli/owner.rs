@@ -0,0 +1,57 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Error, Result, bail, ensure};
+
+use super::symlink::SymlinkDevice;
+use crate::dev::Device;
+
+/// Users and groups that a device is restricted to, as seen inside the container.
+#[derive(Clone)]
+pub struct DeviceOwner {
+    device: SymlinkDevice,
+    pub uids: Vec<u32>,
+    /// Groups are matched against the primary GID of the process only. The filter program cannot
+    /// see supplementary groups, so membership through them does not grant access.
+    pub gids: Vec<u32>,
+}
+
+impl FromStr for DeviceOwner {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((dev, owners)) = s.split_once('=') else {
+            bail!("Owner format should be `<PREFIX>:<DEVICE>=<OWNERS>`, found `{s}`");
+        };
+
+        let mut uids = Vec::new();
+        let mut gids = Vec::new();
+        for owner in owners.split('+') {
+            let (kind, id) = owner.split_once(':').with_context(|| {
+                format!("OWNER should be `uid:<ID>` or `gid:<ID>`, found `{owner}`")
+            })?;
+            let id = id
+                .parse()
+                .with_context(|| format!("OWNER ID should be a number, found `{id}`"))?;
+            match kind {
+                "uid" => uids.push(id),
+                "gid" => gids.push(id),
+                _ => bail!("OWNER should be `uid:<ID>` or `gid:<ID>`, found `{owner}`"),
+            }
+        }
+        ensure!(
+            uids.len() <= 8 && gids.len() <= 8,
+            "At most 8 UIDs and 8 GIDs can own a device, found `{owners}`"
+        );
+
+        Ok(DeviceOwner {
+            device: dev.parse()?,
+            uids,
+            gids,
+        })
+    }
+}
+
+impl DeviceOwner {
+    pub fn matches(&self, device: &Device) -> bool {
+        self.device.matches(device)
+    }
+}
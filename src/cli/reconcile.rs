@@ -0,0 +1,17 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result};
+
+/// How often attached devices are reconciled against the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReconcileInterval(pub Option<Duration>);
+
+impl FromStr for ReconcileInterval {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(Some(humantime::parse_duration(s).with_context(
+            || format!("Reconcile interval should be a duration, found `{s}`"),
+        )?)))
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result, bail, ensure};
+
+use super::DeviceRef;
+
+/// A device that must be attached before the container is started.
+#[derive(Clone)]
+pub enum Required {
+    /// A device with the given symlink.
+    Symlink(PathBuf),
+    /// The referenced device, or any device under it.
+    Device(DeviceRef),
+    /// A device with the given udev property.
+    Property(String, String),
+}
+
+impl FromStr for Required {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((kind, value)) = s.split_once(':') else {
+            bail!("Required device format should be `<KIND>:<VALUE>`, found `{s}`");
+        };
+        match kind {
+            "symlink" => {
+                ensure!(
+                    value.starts_with('/'),
+                    "Required symlink should be an absolute path, found `{value}`"
+                );
+                Ok(Required::Symlink(PathBuf::from(value)))
+            }
+            "device" => Ok(Required::Device(value.parse()?)),
+            "property" => {
+                let (key, value) = value.split_once('=').with_context(|| {
+                    format!("Required property should be `<KEY>=<VALUE>`, found `{value}`")
+                })?;
+                Ok(Required::Property(key.to_owned(), value.to_owned()))
+            }
+            _ => bail!(
+                "Required device KIND should be one of `symlink`, `device` or `property`, found `{kind}`"
+            ),
+        }
+    }
+}
+
+impl Display for Required {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Required::Symlink(path) => write!(f, "symlink {}", path.display()),
+            Required::Device(device) => write!(f, "device {device}"),
+            Required::Property(key, value) => write!(f, "property {key}={value}"),
+        }
+    }
+}
+
+/// How long to wait for required devices before giving up.
+#[derive(Clone, Copy)]
+pub struct RequiredTimeout(pub Duration);
+
+impl Default for RequiredTimeout {
+    fn default() -> Self {
+        Self(Duration::from_secs(30))
+    }
+}
+
+impl FromStr for RequiredTimeout {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(humantime::parse_duration(s).with_context(|| {
+            format!("Timeout should be a duration, found `{s}`")
+        })?))
+    }
+}
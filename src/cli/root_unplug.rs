@@ -0,0 +1,77 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result, bail};
+use rustix::process::Signal;
+
+/// Signals that can be sent to the container when a root device is unplugged.
+const SIGNALS: &[(&str, Signal)] = &[
+    ("HUP", Signal::HUP),
+    ("INT", Signal::INT),
+    ("QUIT", Signal::QUIT),
+    ("USR1", Signal::USR1),
+    ("USR2", Signal::USR2),
+    ("TERM", Signal::TERM),
+];
+
+/// Grace period before the container is killed, if not specified.
+const DEFAULT_GRACE: Duration = Duration::from_secs(10);
+
+/// What happens to the container when one of its root devices is unplugged.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum RootUnplug {
+    /// Kill the container immediately.
+    #[default]
+    Kill,
+    /// Send a signal to the container, and kill it if it does not stop within the grace period.
+    Signal {
+        name: &'static str,
+        signal: Signal,
+        grace: Duration,
+    },
+    /// Keep the container running and wait for the root device to come back, killing the
+    /// container if it does not come back in time.
+    Wait { timeout: Option<Duration> },
+    /// Keep the container running.
+    Ignore,
+}
+
+fn parse_duration(duration: &str) -> Result<Duration> {
+    humantime::parse_duration(duration)
+        .with_context(|| format!("DURATION is invalid, found `{duration}`"))
+}
+
+impl FromStr for RootUnplug {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (policy, args) = s.split_once(':').unwrap_or((s, ""));
+        match (policy, args) {
+            ("kill", "") => Ok(RootUnplug::Kill),
+            ("ignore", "") => Ok(RootUnplug::Ignore),
+            ("wait", "") => Ok(RootUnplug::Wait { timeout: None }),
+            ("wait", timeout) => Ok(RootUnplug::Wait {
+                timeout: Some(parse_duration(timeout)?),
+            }),
+            ("signal", args) => {
+                let (signal, grace) = match args.split_once(':') {
+                    Some((signal, grace)) => (signal, parse_duration(grace)?),
+                    None => (args, DEFAULT_GRACE),
+                };
+                let signal = signal.strip_prefix("SIG").unwrap_or(signal);
+                let Some(&(name, signal)) = SIGNALS.iter().find(|(name, _)| *name == signal) else {
+                    bail!(
+                        "SIGNAL should be one of `HUP`, `INT`, `QUIT`, `USR1`, `USR2` or `TERM`, found `{signal}`"
+                    );
+                };
+                Ok(RootUnplug::Signal {
+                    name,
+                    signal,
+                    grace,
+                })
+            }
+            _ => bail!(
+                "Root unplug policy should be one of `kill`, `signal:<SIGNAL>[:<GRACE>]`, `wait[:<TIMEOUT>]` or `ignore`, found `{s}`"
+            ),
+        }
+    }
+}
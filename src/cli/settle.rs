@@ -0,0 +1,17 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result};
+
+/// How long the device tree must be quiet before a burst of events is applied.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Settle(pub Option<Duration>);
+
+impl FromStr for Settle {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(Some(humantime::parse_duration(s).with_context(
+            || format!("Settle window should be a duration, found `{s}`"),
+        )?)))
+    }
+}
@@ -24,27 +24,11 @@ fn is_hex4(val: &str) -> bool {
     val.len() == 4 && val.chars().all(|c| c.is_ascii_hexdigit())
 }
 
-impl FromStr for Symlink {
+impl FromStr for SymlinkDevice {
     type Err = Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<_> = s.split('=').collect();
-        ensure!(
-            parts.len() == 2,
-            "Symlink format should be `<PREFIX>:<DEVICE>=<PATH>`, found `{s}`"
-        );
-
-        let dev = parts[0];
-        let path = parts[1];
-
-        ensure!(
-            path.starts_with('/') && !path.ends_with('/'),
-            "Symlink PATH should be an absolute file path, found `{path}`."
-        );
-
-        let path = PathBuf::from(path);
-
+    fn from_str(dev: &str) -> Result<Self, Self::Err> {
         let Some((kind, dev)) = dev.split_once(':') else {
-            bail!("Symlink DEVICE format should be `<PREFIX>:<DEVICE>`, found `{dev}`");
+            bail!("DEVICE format should be `<PREFIX>:<DEVICE>`, found `{dev}`");
         };
 
         match kind {
@@ -52,7 +36,7 @@ impl FromStr for Symlink {
                 let parts: Vec<_> = dev.split(':').collect();
                 ensure!(
                     parts.len() == 3,
-                    "Symlink DEVICE format for usb should be `<VID>:<PID>:<INTERFACE>`, found `{dev}`."
+                    "DEVICE format for usb should be `<VID>:<PID>:<INTERFACE>`, found `{dev}`."
                 );
 
                 let vid = parts[0];
@@ -61,33 +45,54 @@ impl FromStr for Symlink {
 
                 ensure!(
                     is_hex4(vid),
-                    "USB symlink VID should be a 4 digit hex number, found `{vid}`"
+                    "USB device VID should be a 4 digit hex number, found `{vid}`"
                 );
                 ensure!(
                     is_hex4(pid),
-                    "USB symlink PID should be a 4 digit hex number, found `{pid}`"
+                    "USB device PID should be a 4 digit hex number, found `{pid}`"
                 );
                 ensure!(
                     !if_num.is_empty() && if_num.chars().all(|c| c.is_ascii_digit()),
-                    "USB symlink INTERFACE should be a number, found `{if_num}`"
+                    "USB device INTERFACE should be a number, found `{if_num}`"
                 );
 
                 let vid = vid.to_ascii_lowercase();
                 let pid = pid.to_ascii_lowercase();
                 let if_num = format!("{if_num:0>2}");
 
-                Ok(Symlink {
-                    device: SymlinkDevice::Usb { vid, pid, if_num },
-                    path,
-                })
+                Ok(SymlinkDevice::Usb { vid, pid, if_num })
             }
             _ => {
-                bail!("Symlink PREFIX should be `usb`, found `{kind}`");
+                bail!("DEVICE PREFIX should be `usb`, found `{kind}`");
             }
         }
     }
 }
 
+impl FromStr for Symlink {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split('=').collect();
+        ensure!(
+            parts.len() == 2,
+            "Symlink format should be `<PREFIX>:<DEVICE>=<PATH>`, found `{s}`"
+        );
+
+        let dev = parts[0];
+        let path = parts[1];
+
+        ensure!(
+            path.starts_with('/') && !path.ends_with('/'),
+            "Symlink PATH should be an absolute file path, found `{path}`."
+        );
+
+        Ok(Symlink {
+            device: dev.parse()?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
 impl SymlinkDevice {
     fn matches_impl(&self, device: &udev::Device) -> Option<bool> {
         let matches = match self {
@@ -3,7 +3,7 @@ use std::path::{Path, PathBuf};
 
 use crate::cgroup::DeviceType;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct DevNode {
     pub path: PathBuf,
     pub ty: DeviceType,
@@ -1,4 +1,4 @@
 mod device;
 mod monitor;
-pub use device::Device;
+pub use device::{DevNode, Device};
 pub use monitor::{DeviceEvent, DeviceMonitor};
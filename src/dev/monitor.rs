@@ -2,43 +2,83 @@
 //!
 //! Provides async support and convience methods to monitor devices and retrieve device properties.
 
-use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::collections::hash_map::Entry;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::task::{Poll, ready};
 
 use anyhow::Result;
+use rustix::io::Errno;
 use tokio::io::unix::AsyncFd;
 use udev::{Enumerator, EventType};
 
 use super::Device;
+use crate::cli::DeviceRef;
+
+/// Largest receive buffer the udev socket is grown to after it overflows. This matches the
+/// buffer size that systemd-udevd uses.
+const MAX_RECEIVE_BUFFER: usize = 128 * 1024 * 1024;
 
 pub enum DeviceEvent {
     Add(Device),
     Remove(Device),
+    /// A root has been found at a new syspath. This precedes `Add` events of devices under it.
+    RootMoved {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Properties of a device have changed.
+    Change(Device),
+    /// A device has been renamed, and is now at a new syspath.
+    Move {
+        from: PathBuf,
+        device: Device,
+    },
+    /// A driver has been bound to a device.
+    Bind(Device),
+    /// A driver has been unbound from a device.
+    Unbind(Device),
+}
+
+struct Root {
+    path: PathBuf,
+    /// Reference that the root is resolved from, to find it again if it reappears elsewhere.
+    device_ref: Option<DeviceRef>,
+}
+
+fn properties(device: &Device) -> Vec<(OsString, OsString)> {
+    device
+        .udev()
+        .properties()
+        .map(|property| (property.name().to_owned(), property.value().to_owned()))
+        .collect()
 }
 
 pub struct DeviceMonitor {
-    /// Root paths for devices to monitor. This is usually a USB hub.
-    roots: Vec<PathBuf>,
+    /// Roots of devices to monitor. This is usually a USB hub.
+    roots: Vec<Root>,
     /// Udev monitor socket.
     // Use `Rc` to avoid lifecycle issues in async stream impl.
     socket: Rc<AsyncFd<udev::MonitorSocket>>,
     /// All devices seen so far. This stores devnode and devnum which
     /// may not be available when the device is removed.
     seen: HashMap<PathBuf, Device>,
-    /// Enumerated devices that are available when the monitor is started.
-    /// Initial reads are from this list.
-    enumerated: VecDeque<Device>,
+    /// Devices under a root that have been forgotten, along with everything under them. They are
+    /// ignored until they are actually removed, or attached again as a root.
+    forgotten: HashSet<PathBuf>,
+    /// Events that are synthesized rather than received from the socket, e.g. enumerated devices
+    /// that are available when the monitor is started. Reads are from this list first.
+    pending: VecDeque<DeviceEvent>,
 }
 
 impl DeviceMonitor {
     /// Create a new device monitor.
     ///
     /// Devices that are already plugged will each generate an `Add` event immediately.
-    pub fn new(roots: Vec<PathBuf>) -> Result<Self> {
+    pub fn new(roots: Vec<(DeviceRef, PathBuf)>) -> Result<Self> {
         // Create a socket before enumerating devices to avoid missing events.
         let socket = Rc::new(AsyncFd::new(udev::MonitorBuilder::new()?.listen()?)?);
 
@@ -46,56 +86,341 @@ impl DeviceMonitor {
         let mut enumerator = Enumerator::new()?;
         let enumerated = enumerator
             .scan_devices()?
-            .filter(|device| roots.iter().any(|root| device.syspath().starts_with(root)))
+            .filter(|device| {
+                roots
+                    .iter()
+                    .any(|(_, root)| device.syspath().starts_with(root))
+            })
             .map(Device::from_udev)
-            .collect::<VecDeque<_>>();
+            .collect::<Vec<_>>();
 
         let mut seen = HashMap::new();
-        for device in &enumerated {
+        let mut pending = VecDeque::new();
+        for device in enumerated {
             seen.insert(device.syspath().to_owned(), device.clone());
+            pending.push_back(DeviceEvent::Add(device));
         }
 
         Ok(Self {
-            roots,
+            roots: roots
+                .into_iter()
+                .map(|(device_ref, path)| Root {
+                    path,
+                    device_ref: Some(device_ref),
+                })
+                .collect(),
             socket,
             seen,
-            enumerated,
+            forgotten: HashSet::new(),
+            pending,
         })
     }
 
+    /// Start monitoring devices under an additional root.
+    ///
+    /// Devices under the root that are already plugged will each generate an `Add` event.
+    pub fn add_root(&mut self, root: PathBuf, device_ref: Option<DeviceRef>) -> Result<()> {
+        self.forgotten.retain(|path| !path.starts_with(&root));
+        self.enumerate(&root)?;
+        if !self.roots.iter().any(|existing| existing.path == root) {
+            self.roots.push(Root {
+                path: root,
+                device_ref,
+            });
+        }
+        Ok(())
+    }
+
+    /// Generate `Add` events for devices under a path that have not been seen.
+    fn enumerate(&mut self, root: &Path) -> Result<()> {
+        let mut enumerator = Enumerator::new()?;
+        let enumerated: Vec<_> = enumerator
+            .scan_devices()?
+            .filter(|device| device.syspath().starts_with(root))
+            .collect();
+        for device in enumerated {
+            if let Entry::Vacant(entry) = self.seen.entry(device.syspath().to_owned()) {
+                let device = Device::from_udev(device);
+                entry.insert(device.clone());
+                self.pending.push_back(DeviceEvent::Add(device));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether a newly added device reveals a root that has disappeared from its syspath,
+    /// e.g. because a hub is plugged into another port, and adopt the new syspath if so.
+    ///
+    /// Returns whether any root has moved.
+    fn relocate_roots(&mut self, added: &Path) -> Result<bool> {
+        let mut moved = Vec::new();
+        for root in &mut self.roots {
+            let Some(device_ref) = &root.device_ref else {
+                continue;
+            };
+            if root.path.exists() {
+                continue;
+            }
+            // The reference may not resolve until devices under the root appear, e.g. for
+            // `parent-of:` references.
+            let Ok(device) = device_ref.device() else {
+                continue;
+            };
+            if !added.starts_with(device.syspath()) {
+                continue;
+            }
+            let from = std::mem::replace(&mut root.path, device.syspath().to_owned());
+            log::info!(
+                "Root {device_ref} moved from {} to {}",
+                from.display(),
+                root.path.display()
+            );
+            moved.push((from, root.path.clone()));
+        }
+
+        let relocated = !moved.is_empty();
+        for (from, to) in moved {
+            self.pending.push_back(DeviceEvent::RootMoved {
+                from,
+                to: to.clone(),
+            });
+            self.enumerate(&to)?;
+        }
+        Ok(relocated)
+    }
+
+    /// Forget about devices under a path, as if they have been removed.
+    ///
+    /// Each device will generate a `Remove` event, children before parents. If the path is a root
+    /// it is no longer monitored, otherwise devices under it will be picked up again once the
+    /// device at the path has been removed and is added again.
+    pub fn forget(&mut self, path: &Path) {
+        self.roots.retain(|root| root.path != path);
+        if self.covers(path) {
+            self.forgotten.insert(path.to_owned());
+        }
+        let mut removed: Vec<_> = self
+            .seen
+            .keys()
+            .filter(|syspath| syspath.starts_with(path))
+            .cloned()
+            .collect();
+        removed.sort_by_key(|syspath| std::cmp::Reverse(syspath.components().count()));
+        for syspath in removed {
+            let device = self.seen.remove(&syspath).unwrap();
+            self.pending.push_back(DeviceEvent::Remove(device));
+        }
+    }
+
+    /// Check if devices under a path are monitored.
+    pub fn covers(&self, path: &Path) -> bool {
+        self.roots.iter().any(|root| path.starts_with(&root.path))
+    }
+
+    fn is_forgotten(&self, path: &Path) -> bool {
+        self.forgotten
+            .iter()
+            .any(|forgotten| path.starts_with(forgotten))
+    }
+
+    /// Rescan partitions of a disk after its partition table is re-read.
+    ///
+    /// The kernel generates events for the partitions it drops and adds, but these are easy to
+    /// miss or reorder, so reconcile against the actual state of the disk instead. A partition
+    /// recreated with a different device number is removed and added again, and one whose
+    /// properties have changed, e.g. its name, generates a `Change` event.
+    fn rescan_partitions(&mut self, disk: &udev::Device) -> Result<()> {
+        let mut enumerator = Enumerator::new()?;
+        enumerator.match_parent(disk)?;
+        enumerator.match_subsystem("block")?;
+        enumerator.match_property("DEVTYPE", "partition")?;
+        let partitions: HashMap<_, _> = enumerator
+            .scan_devices()?
+            .map(|device| (device.syspath().to_owned(), device))
+            .collect();
+
+        let removed: Vec<_> = self
+            .seen
+            .iter()
+            .filter(|(syspath, device)| {
+                syspath.starts_with(disk.syspath())
+                    && device.udev().devtype().is_some_and(|ty| ty == "partition")
+                    && !partitions.contains_key(*syspath)
+            })
+            .map(|(syspath, _)| syspath.clone())
+            .collect();
+        for syspath in removed {
+            let device = self.seen.remove(&syspath).unwrap();
+            self.pending.push_back(DeviceEvent::Remove(device));
+        }
+
+        for (syspath, device) in partitions {
+            // Properties such as the partition name are only available once udevd has processed
+            // the partition, in which case its `add` event will follow.
+            if !device.is_initialized() || self.is_forgotten(&syspath) {
+                continue;
+            }
+            let device = Device::from_udev(device);
+            match self.seen.entry(syspath) {
+                Entry::Vacant(entry) => {
+                    entry.insert(device.clone());
+                    self.pending.push_back(DeviceEvent::Add(device));
+                }
+                Entry::Occupied(mut entry) => {
+                    let devnum = |device: &Device| device.devnode().map(|devnode| devnode.devnum);
+                    if devnum(entry.get()) != devnum(&device) {
+                        let old = entry.insert(device.clone());
+                        self.pending.push_back(DeviceEvent::Remove(old));
+                        self.pending.push_back(DeviceEvent::Add(device));
+                    } else if properties(entry.get()) != properties(&device) {
+                        entry.insert(device.clone());
+                        self.pending.push_back(DeviceEvent::Change(device));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Recover from the udev socket overflowing, in which case events have been lost.
+    ///
+    /// The receive buffer is enlarged to make this less likely to happen again, and the roots are
+    /// enumerated to generate the events that have been missed.
+    fn resync(&mut self) -> Result<()> {
+        let socket = self.socket.get_ref();
+        let size = rustix::net::sockopt::socket_recv_buffer_size(socket)?;
+        if size < MAX_RECEIVE_BUFFER {
+            let size = (size * 2).min(MAX_RECEIVE_BUFFER);
+            // Forcing the size past the system limit requires CAP_NET_ADMIN.
+            if let Err(err) = rustix::net::sockopt::set_socket_recv_buffer_size_force(socket, size)
+                .or_else(|_| rustix::net::sockopt::set_socket_recv_buffer_size(socket, size))
+            {
+                log::warn!("Cannot enlarge udev receive buffer: {err}");
+            }
+        }
+        log::warn!("Udev events lost due to buffer overflow, resynchronizing devices");
+        self.rescan()
+    }
+
+    /// Enumerate devices under the roots again, generating `Add` and `Remove` events for devices
+    /// that have been plugged or unplugged without an event being received.
+    pub fn rescan(&mut self) -> Result<()> {
+        let mut enumerator = Enumerator::new()?;
+        let present: HashMap<_, _> = enumerator
+            .scan_devices()?
+            .filter(|device| self.covers(device.syspath()))
+            .map(|device| (device.syspath().to_owned(), device))
+            .collect();
+        // Forgotten devices that are gone have been removed without an event being received.
+        self.forgotten.retain(|path| present.contains_key(path));
+
+        // Children before parents, as if they are unplugged.
+        let mut removed: Vec<_> = self
+            .seen
+            .keys()
+            .filter(|syspath| !present.contains_key(*syspath))
+            .cloned()
+            .collect();
+        removed.sort_by_key(|syspath| std::cmp::Reverse(syspath.components().count()));
+        for syspath in removed {
+            let device = self.seen.remove(&syspath).unwrap();
+            self.pending.push_back(DeviceEvent::Remove(device));
+        }
+
+        // Parents before children, as if they are plugged.
+        let mut added: Vec<_> = present
+            .into_iter()
+            .filter(|(syspath, _)| !self.seen.contains_key(syspath) && !self.is_forgotten(syspath))
+            .collect();
+        added.sort_by_key(|(syspath, _)| syspath.components().count());
+        for (syspath, device) in added {
+            let device = Device::from_udev(device);
+            self.seen.insert(syspath, device.clone());
+            self.pending.push_back(DeviceEvent::Add(device));
+        }
+
+        Ok(())
+    }
+
     pub fn try_read(&mut self) -> Result<Option<DeviceEvent>> {
-        if let Some(device) = self.enumerated.pop_front() {
-            return Ok(Some(DeviceEvent::Add(device)));
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(Some(event));
         }
 
         loop {
             let Some(event) = self.socket.get_ref().iter().next() else {
+                // libudev reports why no event is received via errno.
+                if Errno::from_io_error(&std::io::Error::last_os_error()) == Some(Errno::NOBUFS) {
+                    self.resync()?;
+                    if let Some(event) = self.pending.pop_front() {
+                        return Ok(Some(event));
+                    }
+                    continue;
+                }
                 return Ok(None);
             };
 
             match event.event_type() {
-                EventType::Add
-                    if self
-                        .roots
-                        .iter()
-                        .any(|root| event.syspath().starts_with(root)) =>
-                {
-                    match self.seen.entry(event.syspath().to_owned()) {
-                        Entry::Occupied(occupied) => {
-                            log::info!("Device already seen: {}", occupied.key().display());
-                        }
-                        Entry::Vacant(entry) => {
-                            let device = Device::from_udev(event.device());
-                            entry.insert(device.clone());
-                            return Ok(Some(DeviceEvent::Add(device)));
+                EventType::Add if !self.covers(event.syspath()) => {
+                    if self.relocate_roots(event.syspath())? {
+                        if let Some(event) = self.pending.pop_front() {
+                            return Ok(Some(event));
                         }
                     }
                 }
+                EventType::Add if self.is_forgotten(event.syspath()) => continue,
+                EventType::Add => match self.seen.entry(event.syspath().to_owned()) {
+                    Entry::Occupied(occupied) => {
+                        log::info!("Device already seen: {}", occupied.key().display());
+                    }
+                    Entry::Vacant(entry) => {
+                        let device = Device::from_udev(event.device());
+                        entry.insert(device.clone());
+                        return Ok(Some(DeviceEvent::Add(device)));
+                    }
+                },
                 EventType::Remove => {
+                    self.forgotten.remove(event.syspath());
                     if let Some(device) = self.seen.remove(event.syspath()) {
                         return Ok(Some(DeviceEvent::Remove(device)));
                     }
                 }
+                EventType::Change if self.seen.contains_key(event.syspath()) => {
+                    let device = Device::from_udev(event.device());
+                    self.seen
+                        .insert(device.syspath().to_owned(), device.clone());
+                    if event.devtype().is_some_and(|ty| ty == "disk") {
+                        self.rescan_partitions(&event.device())?;
+                    }
+                    return Ok(Some(DeviceEvent::Change(device)));
+                }
+                EventType::Bind | EventType::Unbind if self.seen.contains_key(event.syspath()) => {
+                    let device = Device::from_udev(event.device());
+                    self.seen
+                        .insert(device.syspath().to_owned(), device.clone());
+                    return Ok(Some(match event.event_type() {
+                        EventType::Bind => DeviceEvent::Bind(device),
+                        _ => DeviceEvent::Unbind(device),
+                    }));
+                }
+                EventType::Unknown if event.action().is_some_and(|action| action == "move") => {
+                    let Some(devpath) = event.property_value("DEVPATH_OLD") else {
+                        continue;
+                    };
+                    let from = Path::new("/sys").join(
+                        Path::new(devpath)
+                            .strip_prefix("/")
+                            .unwrap_or(Path::new(devpath)),
+                    );
+                    if self.seen.remove(&from).is_none() {
+                        continue;
+                    }
+                    let device = Device::from_udev(event.device());
+                    self.seen
+                        .insert(device.syspath().to_owned(), device.clone());
+                    return Ok(Some(DeviceEvent::Move { from, device }));
+                }
                 _ => continue,
             }
         }
@@ -109,7 +434,7 @@ impl tokio_stream::Stream for DeviceMonitor {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        if !self.enumerated.is_empty() {
+        if !self.pending.is_empty() {
             return Poll::Ready(self.try_read().transpose());
         }
 
@@ -0,0 +1,89 @@
+//! Removal of state left behind by daemons that did not exit cleanly.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Result;
+use rustix::mount::UnmountFlags;
+use rustix::process::Pid;
+
+use crate::hotplug::{Claim, Journal};
+use crate::runc::cli::GlobalOptions;
+use crate::runc::state::State;
+use crate::util::namespace::MntNamespace;
+
+/// Collect names of all cgroups, with the `.scope` suffix stripped as in pin names.
+fn cgroup_names(dir: &Path, names: &mut HashSet<String>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            names.insert(name.trim_end_matches(".scope").to_owned());
+        }
+        cgroup_names(&entry.path(), names)?;
+    }
+    Ok(())
+}
+
+pub fn gc(global: &GlobalOptions, dry_run: bool) -> Result<()> {
+    let remove = |path: &Path| {
+        println!("Removing {}", path.display());
+        if !dry_run {
+            if let Err(err) = std::fs::remove_file(path) {
+                log::warn!("Cannot remove {}: {}", path.display(), err);
+            }
+        }
+    };
+
+    // Pins are only removed if the cgroup is gone. Removing the pinned link of a live container
+    // would detach its device filter.
+    let mut cgroups = HashSet::new();
+    cgroup_names(Path::new("/sys/fs/cgroup"), &mut cgroups)?;
+    for (id, pins) in crate::cgroup::pinned_filters()? {
+        if !cgroups.contains(&id) {
+            pins.iter().for_each(|pin| remove(pin));
+        }
+    }
+
+    for claim in Claim::stale()? {
+        remove(&claim.path);
+    }
+
+    for id in Journal::list()? {
+        if Journal::in_use(&id)? {
+            continue;
+        }
+
+        let Ok(state) = State::from_root_and_id(&global.root, &id) else {
+            // The container no longer exists.
+            for ext in ["json", "json.tmp", "lock", "sock"] {
+                let path = Path::new(crate::hotplug::RUNTIME_DIR).join(format!("{id}.{ext}"));
+                if path.exists() {
+                    remove(&path);
+                }
+            }
+            continue;
+        };
+
+        // `/olddev` is left behind if `/dev` remounting was interrupted.
+        let Some(pid) = Pid::from_raw(state.init_process_pid as i32) else {
+            continue;
+        };
+        let olddev = format!("/proc/{}/root/olddev", pid.as_raw_nonzero());
+        if !Path::new(&olddev).exists() {
+            continue;
+        }
+        println!("Removing /olddev of container {id}");
+        if !dry_run {
+            MntNamespace::of_pid(pid)?.with(|| -> Result<()> {
+                let _ = rustix::mount::unmount("/olddev", UnmountFlags::DETACH);
+                std::fs::remove_dir("/olddev")?;
+                Ok(())
+            })??;
+        }
+    }
+
+    Ok(())
+}
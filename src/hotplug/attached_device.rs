@@ -1,13 +1,23 @@
 use std::fmt::{self, Display, Formatter};
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use crate::cgroup::{Access, DeviceCounters};
 use crate::dev::Device;
 
 #[derive(Clone)]
 pub struct AttachedDevice {
     pub(super) device: Device,
+    /// Syspath the device is tracked under. This is the syspath of `device`, except for network
+    /// interfaces restored from the journal, where `device` is their parent on the host.
+    pub(super) syspath: PathBuf,
+    pub(super) access: Access,
     pub(super) symlinks: Vec<PathBuf>,
+    pub(super) mount: Option<PathBuf>,
+    /// Name of the network interface inside the container.
+    pub(super) interface: Option<String>,
+    /// Access check counters, available once the device is detached.
+    pub(super) counters: Option<DeviceCounters>,
 }
 
 impl Deref for AttachedDevice {
@@ -18,6 +28,12 @@ impl Deref for AttachedDevice {
     }
 }
 
+impl AttachedDevice {
+    pub fn syspath(&self) -> &Path {
+        &self.syspath
+    }
+}
+
 impl Display for AttachedDevice {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         if let Some(devnode) = self.devnode() {
@@ -40,6 +56,18 @@ impl Display for AttachedDevice {
             write!(f, ", {}", symlink.display())?;
         }
         write!(f, "]")?;
+        if self.devnode().is_some() && !self.access.contains(Access::WRITE) {
+            write!(f, " read-only")?;
+        }
+        if let Some(interface) = &self.interface {
+            write!(f, " as {interface}")?;
+        }
+        if let Some(mount) = &self.mount {
+            write!(f, " mounted on {}", mount.display())?;
+        }
+        if let Some(counters) = &self.counters {
+            write!(f, ", {counters}")?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,143 @@
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_stream::try_stream;
+use tokio_stream::Stream;
+
+use super::Event;
+use crate::cgroup::{Access, Denial, DeviceType, DeviceUsage};
+use crate::runc::Container;
+
+/// Maximum number of denials reported per `RATE_LIMIT_INTERVAL`.
+const RATE_LIMIT_BURST: u32 = 10;
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(10);
+
+fn type_char(ty: DeviceType) -> char {
+    match ty {
+        DeviceType::Block => 'b',
+        DeviceType::Character => 'c',
+    }
+}
+
+/// Resolve the device node of a device on the host.
+fn host_devnode(ty: DeviceType, (major, minor): (u32, u32)) -> Option<PathBuf> {
+    let ty = match ty {
+        DeviceType::Block => udev::DeviceType::Block,
+        DeviceType::Character => udev::DeviceType::Character,
+    };
+    let device = udev::Device::from_devnum(ty, rustix::fs::makedev(major, minor)).ok()?;
+    Some(device.devnode()?.to_owned())
+}
+
+/// A device access denied by the device filter.
+#[derive(Clone)]
+pub struct DeniedAccess {
+    container: String,
+    denial: Denial,
+    /// Device node of the device on the host, if it can be resolved.
+    devnode: Option<PathBuf>,
+    /// Number of denials not reported due to rate limiting since the last report.
+    suppressed: u32,
+}
+
+impl Display for DeniedAccess {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Denial {
+            ty,
+            devnum: (major, minor),
+            access,
+            pid,
+            comm,
+        } = &self.denial;
+        let access = [
+            (Access::READ, 'r'),
+            (Access::WRITE, 'w'),
+            (Access::MKNOD, 'm'),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| access.contains(*flag))
+        .map(|(_, c)| c)
+        .collect::<String>();
+        let ty = type_char(*ty);
+        write!(
+            f,
+            "Container {} denied `{access}` access to {ty} {major:0>3}:{minor:0>3}",
+            self.container
+        )?;
+        if let Some(devnode) = &self.devnode {
+            write!(f, " [{}]", devnode.display())?;
+        }
+        write!(f, " by {comm} ({pid})")?;
+        if self.suppressed != 0 {
+            write!(f, ", {} more suppressed", self.suppressed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Report accesses denied by the container's device filter.
+pub fn audit(container: Arc<Container>) -> impl Stream<Item = Result<Event>> {
+    try_stream! {
+        let Some(mut monitor) = container.take_denials().await? else {
+            return;
+        };
+
+        let mut window = Instant::now();
+        let mut reported = 0;
+        let mut suppressed = 0;
+        loop {
+            let denial = monitor.next().await?;
+
+            if window.elapsed() >= RATE_LIMIT_INTERVAL {
+                window = Instant::now();
+                reported = 0;
+            }
+            if reported >= RATE_LIMIT_BURST {
+                suppressed += 1;
+                continue;
+            }
+            reported += 1;
+
+            let devnode = host_devnode(denial.ty, denial.devnum);
+            yield Event::Denied(DeniedAccess {
+                container: container.id().to_owned(),
+                denial,
+                devnode,
+                suppressed: std::mem::take(&mut suppressed),
+            });
+        }
+    }
+}
+
+/// Access check counters of a device over the lifetime of the container.
+pub struct UsageReport {
+    usage: DeviceUsage,
+    devnode: Option<PathBuf>,
+}
+
+impl Display for UsageReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (major, minor) = self.usage.devnum;
+        write!(f, "{} {major:0>3}:{minor:0>3}", type_char(self.usage.ty))?;
+        if let Some(devnode) = &self.devnode {
+            write!(f, " [{}]", devnode.display())?;
+        }
+        write!(f, " {}", self.usage.counters)
+    }
+}
+
+/// Summarize device usage of the container.
+pub async fn usage(container: &Container) -> Result<Vec<UsageReport>> {
+    let mut usage = container.all_counters().await?;
+    usage.sort_by_key(|usage| (usage.ty as u32, usage.devnum));
+    Ok(usage
+        .into_iter()
+        .map(|usage| UsageReport {
+            devnode: host_devnode(usage.ty, usage.devnum),
+            usage,
+        })
+        .collect())
+}
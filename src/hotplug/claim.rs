@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+use rustix::fs::FlockOperation;
+use rustix::io::Errno;
+
+use super::RUNTIME_DIR;
+
+fn claims_dir() -> PathBuf {
+    Path::new(RUNTIME_DIR).join("claims")
+}
+
+/// A claim on a device by a container, so that containers given overlapping devices do not use
+/// the same device at the same time.
+///
+/// Claims are lock files keyed by syspath. A device can be claimed by a single container
+/// exclusively, or by any number of containers if it is shareable. The lock is released when
+/// the claim is dropped or the daemon exits.
+pub struct Claim {
+    path: PathBuf,
+    file: File,
+}
+
+impl Claim {
+    /// Claim a device for a container, failing if it is already claimed by another one.
+    pub fn acquire(syspath: &Path, id: &str, shared: bool) -> Result<Self> {
+        std::fs::create_dir_all(claims_dir())?;
+        let name = syspath
+            .strip_prefix("/sys")
+            .unwrap_or(syspath)
+            .to_string_lossy()
+            .trim_start_matches('/')
+            .replace('/', "!");
+        let path = claims_dir().join(name);
+        let operation = if shared {
+            FlockOperation::NonBlockingLockShared
+        } else {
+            FlockOperation::NonBlockingLockExclusive
+        };
+
+        loop {
+            let mut file = File::options()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&path)?;
+            match rustix::fs::flock(&file, operation) {
+                Ok(()) => (),
+                Err(Errno::WOULDBLOCK) => {
+                    let holder = std::fs::read_to_string(&path).unwrap_or_default();
+                    if holder.is_empty() {
+                        bail!("Device {} is shared by other containers", syspath.display());
+                    }
+                    bail!(
+                        "Device {} is claimed by container {}",
+                        syspath.display(),
+                        holder
+                    );
+                }
+                Err(err) => Err(err)?,
+            }
+
+            // The previous holder may have removed the file after we opened it, in which case
+            // the lock is on a stale file.
+            let current = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => Err(err)?,
+            };
+            if current.ino() != file.metadata()?.ino() {
+                continue;
+            }
+
+            if !shared {
+                file.set_len(0)?;
+                file.write_all(id.as_bytes())?;
+            }
+            return Ok(Self { path, file });
+        }
+    }
+
+    /// Claims that are no longer held by any container.
+    ///
+    /// Each stale claim is locked until it is dropped, so that it can be removed without racing
+    /// with a container claiming the device.
+    pub fn stale() -> Result<Vec<StaleClaim>> {
+        let mut stale = Vec::new();
+        let entries = match std::fs::read_dir(claims_dir()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(stale),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let file = File::open(&path)?;
+            if rustix::fs::flock(&file, FlockOperation::NonBlockingLockExclusive).is_ok() {
+                stale.push(StaleClaim { path, _lock: file });
+            }
+        }
+        Ok(stale)
+    }
+}
+
+/// A claim that is no longer held by any container.
+pub struct StaleClaim {
+    pub path: PathBuf,
+    _lock: File,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        // Only remove the file if no other container shares the claim.
+        if rustix::fs::flock(&self.file, FlockOperation::NonBlockingLockExclusive).is_ok() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use super::RUNTIME_DIR;
+
+/// Maximum time a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request sent to the daemon over its control socket.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Request {
+    /// Attach a device and its children to the container.
+    Attach(String),
+    /// Detach a device and its children from the container.
+    Detach(String),
+    /// Query the device lease, optionally extending it.
+    Lease { extend: Option<Duration> },
+    /// Reconcile attached devices against the host, repairing any drift.
+    Reconcile,
+}
+
+/// Response to a request, a human readable message either way.
+pub type Response = Result<String, String>;
+
+fn socket_path(id: &str) -> PathBuf {
+    Path::new(RUNTIME_DIR).join(format!("{id}.sock"))
+}
+
+/// Control socket of a daemon, which allows devices to be managed from the command line.
+pub struct ControlSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    /// Requests read by per-connection tasks, so a slow client does not hold up the daemon.
+    sender: mpsc::Sender<(Connection, Result<Request>)>,
+    receiver: mpsc::Receiver<(Connection, Result<Request>)>,
+}
+
+/// A client connection.
+pub struct Connection {
+    stream: UnixStream,
+}
+
+impl ControlSocket {
+    pub fn bind(id: &str) -> Result<Self> {
+        std::fs::create_dir_all(RUNTIME_DIR)?;
+        let path = socket_path(id);
+        // Only one daemon manages a container, so a socket already present is left over by a
+        // previous daemon.
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Cannot bind control socket {}", path.display()))?;
+        let (sender, receiver) = mpsc::channel(16);
+        Ok(Self {
+            path,
+            listener,
+            sender,
+            receiver,
+        })
+    }
+
+    /// Wait for the next request of any client. This is cancel safe.
+    ///
+    /// Requests are read concurrently in the background, so clients are served in the order in
+    /// which their requests complete.
+    pub async fn next(&mut self) -> Result<(Connection, Result<Request>)> {
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (stream, _) = accepted?;
+                    let sender = self.sender.clone();
+                    tokio::spawn(async move {
+                        let mut connection = Connection { stream };
+                        let request = connection.request().await;
+                        let _ = sender.send((connection, request)).await;
+                    });
+                }
+                // The socket holds a sender itself, so the channel is never closed.
+                Some(request) = self.receiver.recv() => return Ok(request),
+            }
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Connection {
+    /// Read the request, which the client terminates by shutting down its side of the stream.
+    async fn request(&mut self) -> Result<Request> {
+        let mut buf = Vec::new();
+        tokio::time::timeout(REQUEST_TIMEOUT, self.stream.read_to_end(&mut buf))
+            .await
+            .context("Timed out reading request")??;
+        serde_json::from_slice(&buf).context("Malformed request")
+    }
+
+    async fn write(&mut self, response: &Response) -> Result<()> {
+        self.stream
+            .write_all(&serde_json::to_vec(response)?)
+            .await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Reply to the request. The client going away is not an error of the daemon.
+    pub async fn reply(mut self, response: Response) {
+        if let Err(err) = self.write(&response).await {
+            log::warn!("Cannot reply to control request: {err:#}");
+        }
+    }
+}
+
+/// Send a request to the daemon managing a container and wait for its response.
+pub fn request(id: &str, request: &Request) -> Result<String> {
+    let path = socket_path(id);
+    let mut stream = std::os::unix::net::UnixStream::connect(&path)
+        .with_context(|| format!("No daemon is managing container {id}"))?;
+    stream.write_all(&serde_json::to_vec(request)?)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    let response: Response = serde_json::from_slice(&buf).context("Malformed response")?;
+    match response {
+        Ok(message) => Ok(message),
+        Err(message) => bail!("{message}"),
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rustix::fs::FlockOperation;
+use serde::{Deserialize, Serialize};
+
+use super::AttachedDevice;
+use crate::dev::DevNode;
+
+/// Directory for runtime state of container-hotplug.
+pub const RUNTIME_DIR: &str = "/run/container-hotplug";
+
+/// A device attached to the container, as recorded in the journal.
+#[derive(Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub syspath: PathBuf,
+    pub devnode: Option<DevNode>,
+    pub access: u32,
+    pub symlinks: Vec<PathBuf>,
+    pub mount: Option<PathBuf>,
+    pub interface: Option<String>,
+}
+
+/// Journal of devices attached to a container, so that a new daemon can pick up where a previous
+/// one left off.
+///
+/// The journal is locked while a daemon is attached to the container.
+pub struct Journal {
+    path: PathBuf,
+    _lock: File,
+    entries: BTreeMap<PathBuf, JournalEntry>,
+}
+
+impl Journal {
+    fn lock(id: &str) -> Result<(PathBuf, File)> {
+        std::fs::create_dir_all(RUNTIME_DIR)?;
+        let path = Path::new(RUNTIME_DIR).join(format!("{id}.json"));
+        let lock_path = Path::new(RUNTIME_DIR).join(format!("{id}.lock"));
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        rustix::fs::flock(&lock, FlockOperation::NonBlockingLockExclusive)
+            .with_context(|| format!("Container {id} is already managed by another daemon"))?;
+        Ok((path, lock))
+    }
+
+    /// Create an empty journal for a new container.
+    pub fn create(id: &str) -> Result<Self> {
+        let (path, lock) = Self::lock(id)?;
+        let journal = Self {
+            path,
+            _lock: lock,
+            entries: BTreeMap::new(),
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Open the journal of an existing container.
+    pub fn open(id: &str) -> Result<Self> {
+        let (path, lock) = Self::lock(id)?;
+        let entries: Vec<JournalEntry> = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).context("Cannot parse journal")?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => Err(err).context("Cannot read journal")?,
+        };
+        Ok(Self {
+            path,
+            _lock: lock,
+            entries: entries
+                .into_iter()
+                .map(|entry| (entry.syspath.clone(), entry))
+                .collect(),
+        })
+    }
+
+    /// Take all entries out of the journal. They should be inserted again if still attached.
+    pub fn take_entries(&mut self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.entries).into_values().collect()
+    }
+
+    pub fn insert(&mut self, device: &AttachedDevice) -> Result<()> {
+        let syspath = device.syspath().to_owned();
+        self.entries.insert(
+            syspath.clone(),
+            JournalEntry {
+                syspath,
+                devnode: device.devnode().cloned(),
+                access: device.access.bits(),
+                symlinks: device.symlinks.clone(),
+                mount: device.mount.clone(),
+                interface: device.interface.clone(),
+            },
+        );
+        self.save()
+    }
+
+    pub fn remove(&mut self, syspath: &Path) -> Result<()> {
+        if self.entries.remove(syspath).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        // Write to a temporary file and rename, so the journal is never seen partially written.
+        let temp = self.path.with_extension("json.tmp");
+        let entries: Vec<_> = self.entries.values().collect();
+        std::fs::write(&temp, serde_json::to_vec(&entries)?)?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+
+    /// IDs of containers that have a journal.
+    pub fn list() -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let entries = match std::fs::read_dir(RUNTIME_DIR) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if let Some(id) = name
+                .strip_suffix(".lock")
+                .or_else(|| name.strip_suffix(".json"))
+            {
+                if !ids.iter().any(|existing| existing == id) {
+                    ids.push(id.to_owned());
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Check if a daemon is attached to the container.
+    pub fn in_use(id: &str) -> Result<bool> {
+        let lock_path = Path::new(RUNTIME_DIR).join(format!("{id}.lock"));
+        let lock = match File::open(&lock_path) {
+            Ok(lock) => lock,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(rustix::fs::flock(&lock, FlockOperation::NonBlockingLockShared).is_err())
+    }
+
+    /// Remove the journal of a container once it is gone.
+    pub fn discard(id: &str) {
+        let _ = std::fs::remove_file(Path::new(RUNTIME_DIR).join(format!("{id}.json")));
+        let _ = std::fs::remove_file(Path::new(RUNTIME_DIR).join(format!("{id}.lock")));
+    }
+}
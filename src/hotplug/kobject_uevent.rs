@@ -62,13 +62,29 @@ impl UdevSender {
     }
 
     pub fn send(&mut self, device: &udev::Device, event: &str) -> Result<()> {
+        self.send_with(device, event, &[])
+    }
+
+    /// Send an event with additional properties, which take precedence over those of the device.
+    pub fn send_with(
+        &mut self,
+        device: &udev::Device,
+        event: &str,
+        extra: &[(&str, &str)],
+    ) -> Result<()> {
         self.seq_num += 1;
 
         let mut properties = Vec::new();
         write!(properties, "ACTION={event}\0SEQNUM={}\0", self.seq_num)?;
+        for (name, value) in extra {
+            write!(properties, "{name}={value}\0")?;
+        }
         for property in device.properties() {
             // These properties are specially handled.
-            if property.name() == "ACTION" || property.name() == "SEQNUM" {
+            if property.name() == "ACTION"
+                || property.name() == "SEQNUM"
+                || extra.iter().any(|(name, _)| property.name() == *name)
+            {
                 continue;
             }
             properties.extend_from_slice(property.name().as_encoded_bytes());
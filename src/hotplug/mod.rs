@@ -1,37 +1,131 @@
 mod attached_device;
+mod audit;
+mod claim;
+mod control;
+mod journal;
 mod kobject_uevent;
 pub use attached_device::AttachedDevice;
+pub use audit::{DeniedAccess, audit, usage};
+pub use claim::Claim;
+use control::{ControlSocket, Request};
+pub use control::{Request as ControlRequest, request};
+use journal::JournalEntry;
+pub use journal::{Journal, RUNTIME_DIR};
 pub use kobject_uevent::UdevSender;
 
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail, ensure};
 use async_stream::try_stream;
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
 
 use super::Event;
-use crate::cgroup::Access;
+use crate::cgroup::{Access, DeviceSet, DeviceType};
 use crate::cli;
-use crate::dev::{DeviceEvent, DeviceMonitor};
+use crate::dev::{DevNode, Device, DeviceEvent, DeviceMonitor};
 use crate::runc::Container;
 
+/// Policies applied to devices as they are attached.
+#[derive(Default)]
+pub struct Options {
+    pub symlinks: Vec<cli::Symlink>,
+    pub mounts: Vec<cli::Mount>,
+    pub interfaces: Vec<cli::NetInterface>,
+    pub block_access: Vec<cli::BlockAccess>,
+    pub io_limits: Vec<cli::IoLimit>,
+    pub device_rules: Vec<cli::DeviceCgroupRule>,
+    pub owners: Vec<cli::DeviceOwner>,
+    pub lock_after_init: cli::LockAfterInit,
+    pub lease: cli::Lease,
+    /// Devices that may be claimed by other containers at the same time.
+    pub shared: Vec<cli::symlink::SymlinkDevice>,
+    /// What happens to the container when a root device is unplugged. This is handled by the
+    /// consumer of `HotPlug::run`.
+    pub root_unplug: cli::RootUnplug,
+    /// Devices that must be attached before the container is initialized.
+    pub required: Vec<cli::Required>,
+    pub required_timeout: cli::RequiredTimeout,
+    pub groups: Vec<cli::DeviceGroup>,
+    pub settle: cli::Settle,
+    pub reconcile_interval: cli::ReconcileInterval,
+}
+
 pub struct HotPlug {
     pub container: Arc<Container>,
-    symlinks: Vec<cli::Symlink>,
+    options: Options,
     monitor: DeviceMonitor,
     devices: HashMap<PathBuf, AttachedDevice>,
     udev_sender: UdevSender,
+    journal: Journal,
+    /// Devices configured by annotations, as opposed to those attached from the command line.
+    hubs: Vec<PathBuf>,
+    control: ControlSocket,
+    /// Identities of devices attached at initialization, once the device set is locked.
+    locked: Option<HashSet<DeviceIdentity>>,
+    /// Lease of devices to the container, which starts at initialization.
+    lease: Option<LeaseState>,
+    /// When attached devices are next reconciled against the host.
+    next_reconcile: Option<Instant>,
+    /// Claims on attached devices, keyed by syspath.
+    claims: HashMap<PathBuf, Claim>,
+    /// Devices of each group in `Options::groups` held back until all members are present, by
+    /// group instance.
+    held: Vec<HashMap<PathBuf, Vec<Device>>>,
+    /// Group and group instance of attached group members.
+    grouped: HashMap<PathBuf, (usize, PathBuf)>,
+    /// Devices whose `add` uevent is deferred until the current batch of events is applied.
+    deferred: Option<Vec<Device>>,
+    /// Input that cut settling short, to be handled next.
+    queued: Option<Input>,
+}
+
+struct LeaseState {
+    expiry: Instant,
+    /// Whether the container has been warned about the lease expiring.
+    warned: bool,
+    expired: bool,
+}
+
+fn round_secs(duration: Duration) -> Duration {
+    Duration::from_secs(duration.as_secs())
+}
+
+/// Identifies a physical device plugged into a specific port.
+type DeviceIdentity = (PathBuf, Option<OsString>);
+
+fn identity(device: &Device) -> DeviceIdentity {
+    (
+        device.syspath().to_owned(),
+        device
+            .udev()
+            .property_value("ID_SERIAL")
+            .map(ToOwned::to_owned),
+    )
+}
+
+enum Input {
+    Device(DeviceEvent),
+    Control(control::Connection, Result<Request>),
+    Lease,
+    Reconcile,
 }
 
 impl HotPlug {
     pub fn new(
         container: Arc<Container>,
-        hub_path: Vec<PathBuf>,
-        symlinks: Vec<cli::Symlink>,
+        roots: Vec<(cli::DeviceRef, PathBuf)>,
+        options: Options,
+        journal: Journal,
     ) -> Result<Self> {
-        let monitor = DeviceMonitor::new(hub_path)?;
+        let hubs = roots.iter().map(|(_, path)| path.clone()).collect();
+        let options_groups = options.groups.len();
+        let monitor = DeviceMonitor::new(roots)?;
+        let control = ControlSocket::bind(container.id())?;
         let devices = Default::default();
 
         let udev_sender = UdevSender::new(crate::util::namespace::NetNamespace::of_pid(
@@ -40,80 +134,1197 @@ impl HotPlug {
 
         Ok(Self {
             container,
-            symlinks,
+            options,
             monitor,
             devices,
             udev_sender,
+            journal,
+            hubs,
+            control,
+            locked: None,
+            lease: None,
+            next_reconcile: None,
+            claims: HashMap::new(),
+            held: vec![HashMap::new(); options_groups],
+            grouped: HashMap::new(),
+            deferred: None,
+            queued: None,
         })
     }
 
     pub fn run(&mut self) -> impl tokio_stream::Stream<Item = Result<Event>> + '_ {
         try_stream! {
+            for rule in &self.options.device_rules {
+                self.container
+                    .devices(rule.ty, rule.set.clone(), rule.access)
+                    .await?;
+            }
+
+            self.restore().await?;
+
             while let Some(event) = self.monitor.try_read()? {
-                if let Some(event) = self.process(event).await? {
+                for event in self.process_and_record(event).await? {
+                    yield event;
+                }
+            }
+
+            // Hold off initialization until the required devices are attached, so that the
+            // workload does not start without them.
+            let deadline = Instant::now() + self.options.required_timeout.0;
+            while !self.missing_required().is_empty() {
+                let Some(event) = self.next_required_event(deadline).await? else {
+                    break;
+                };
+                for event in self.process_and_record(event).await? {
                     yield event;
                 }
             }
 
+            if self.options.lock_after_init != cli::LockAfterInit::Off {
+                self.locked = Some(self.devices.values().map(|device| identity(device)).collect());
+            }
+
+            if let Some(duration) = self.options.lease.duration {
+                let expiry = Instant::now()
+                    .checked_add(duration)
+                    .context("Device lease duration is too long")?;
+                self.lease = Some(LeaseState {
+                    expiry,
+                    warned: false,
+                    expired: false,
+                });
+            }
+
+            self.next_reconcile = self
+                .options
+                .reconcile_interval
+                .0
+                .map(|interval| Instant::now() + interval);
+
             yield Event::Initialized;
 
-            while let Some(event) = self.monitor.try_next().await? {
-                if let Some(event) = self.process(event).await? {
-                    yield event;
+            while let Some(input) = self.next_input().await? {
+                match input {
+                    Input::Device(event) => {
+                        let events = match self.options.settle.0 {
+                            Some(window) => {
+                                let batch = self.settle(event, window).await?;
+                                self.process_batch(batch).await?
+                            }
+                            None => self.process_and_record(event).await?,
+                        };
+                        for event in events {
+                            yield event;
+                        }
+                    }
+                    Input::Reconcile => {
+                        let mut events = Vec::new();
+                        // Drift is repaired again next time, so a failure here is not fatal.
+                        if let Err(err) = self.reconcile(&mut events).await {
+                            log::warn!("Cannot reconcile devices: {err:#}");
+                        }
+                        self.next_reconcile = self
+                            .options
+                            .reconcile_interval
+                            .0
+                            .map(|interval| Instant::now() + interval);
+                        for event in events {
+                            yield event;
+                        }
+                    }
+                    Input::Lease => {
+                        for event in self.lease_timeout().await? {
+                            yield event;
+                        }
+                    }
+                    Input::Control(connection, request) => {
+                        let mut events = Vec::new();
+                        let response = match request {
+                            Ok(request) => self.handle_request(&request, &mut events).await,
+                            Err(err) => Err(err),
+                        };
+                        let response = response.map_err(|err| {
+                            log::warn!("Control request failed: {err:#}");
+                            format!("{err:#}")
+                        });
+                        connection.reply(response).await;
+                        for event in events {
+                            yield event;
+                        }
+                    }
                 }
             }
         }
     }
 
-    async fn process(&mut self, event: DeviceEvent) -> Result<Option<Event>> {
-        match event {
-            DeviceEvent::Add(device) => {
-                let Some(devnode) = device.devnode() else {
-                    return Ok(None);
+    /// Required devices that are not attached.
+    fn missing_required(&self) -> Vec<&cli::Required> {
+        self.options
+            .required
+            .iter()
+            .filter(|required| {
+                // Resolved once, as this goes through the sysfs.
+                let root = match required {
+                    cli::Required::Device(device_ref) => match device_ref.device() {
+                        Ok(root) => Some(root),
+                        Err(_) => return true,
+                    },
+                    _ => None,
                 };
+                !self.devices.values().any(|device| match required {
+                    cli::Required::Symlink(path) => device.symlinks.contains(path),
+                    cli::Required::Device(_) => root
+                        .as_ref()
+                        .is_some_and(|root| device.syspath().starts_with(root.syspath())),
+                    cli::Required::Property(key, value) => device
+                        .udev()
+                        .property_value(key)
+                        .is_some_and(|actual| actual == value.as_str()),
+                })
+            })
+            .collect()
+    }
 
-                let symlinks: Vec<_> = self
-                    .symlinks
-                    .iter()
-                    .filter_map(|dev| dev.matches(&device))
-                    .collect();
+    /// Wait for the next device event while waiting for required devices.
+    async fn next_required_event(&mut self, deadline: Instant) -> Result<Option<DeviceEvent>> {
+        tokio::select! {
+            event = self.monitor.try_next() => return event,
+            _ = tokio::time::sleep_until(deadline) => (),
+        }
+        let missing: Vec<_> = self
+            .missing_required()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        bail!(
+            "Required devices are not attached after {}: {}",
+            humantime::format_duration(self.options.required_timeout.0),
+            missing.join(", ")
+        );
+    }
+
+    /// Wait for the next input. This is cancel safe.
+    async fn next_input(&mut self) -> Result<Option<Input>> {
+        if let Some(input) = self.queued.take() {
+            return Ok(Some(input));
+        }
+        let lease_timer = self.lease_timer();
+        tokio::select! {
+            event = self.monitor.try_next() => Ok(event?.map(Input::Device)),
+            request = self.control.next() => {
+                let (connection, request) = request?;
+                Ok(Some(Input::Control(connection, request)))
+            }
+            _ = tokio::time::sleep_until(lease_timer.unwrap_or_else(Instant::now)),
+                if lease_timer.is_some() => Ok(Some(Input::Lease)),
+            _ = tokio::time::sleep_until(self.next_reconcile.unwrap_or_else(Instant::now)),
+                if self.next_reconcile.is_some() => Ok(Some(Input::Reconcile)),
+        }
+    }
+
+    /// Collect a burst of device events until no more arrive within the settle window.
+    ///
+    /// Events of all roots go into the same batch. The batch is cut off after ten windows, so that
+    /// a device that keeps generating events does not hold back everything else, and as soon as
+    /// any other input arrives, so that control requests and timers are not starved.
+    async fn settle(&mut self, first: DeviceEvent, window: Duration) -> Result<Vec<DeviceEvent>> {
+        let deadline = Instant::now() + window * 10;
+        let mut batch = vec![first];
+        loop {
+            let quiet = (Instant::now() + window).min(deadline);
+            let Ok(input) = tokio::time::timeout_at(quiet, self.next_input()).await else {
+                break;
+            };
+            match input? {
+                Some(Input::Device(event)) => batch.push(event),
+                Some(input) => {
+                    self.queued = Some(input);
+                    break;
+                }
+                None => break,
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Apply a batch of device events, so that the container sees the resulting device tree at
+    /// once rather than a partial one.
+    ///
+    /// Devices added and removed again within the batch are skipped altogether, and devices are
+    /// added parents before children. The `add` uevents are only sent once all devices are in
+    /// place.
+    async fn process_batch(&mut self, batch: Vec<DeviceEvent>) -> Result<Vec<Event>> {
+        let mut coalesced = Vec::new();
+        let mut added = HashMap::new();
+        for event in batch {
+            match &event {
+                DeviceEvent::Add(device) => {
+                    added.insert(device.syspath().to_owned(), coalesced.len());
+                }
+                DeviceEvent::Remove(device) => {
+                    if let Some(index) = added.remove(device.syspath()) {
+                        coalesced[index] = None;
+                        continue;
+                    }
+                }
+                _ => (),
+            }
+            coalesced.push(Some(event));
+        }
+        // Removals first, then additions, then updates of devices that may have just been added.
+        let mut removes = Vec::new();
+        let mut adds = Vec::new();
+        let mut updates = Vec::new();
+        for event in coalesced.into_iter().flatten() {
+            match event {
+                DeviceEvent::Remove(_) | DeviceEvent::RootMoved { .. } => removes.push(event),
+                DeviceEvent::Add(_) => adds.push(event),
+                _ => updates.push(event),
+            }
+        }
+        adds.sort_by_key(|event| match event {
+            DeviceEvent::Add(device) => device.syspath().components().count(),
+            _ => 0,
+        });
+
+        self.deferred = Some(Vec::new());
+        let mut events = Vec::new();
+        let mut result = Ok(());
+        for event in removes.into_iter().chain(adds).chain(updates) {
+            match self.process_and_record(event).await {
+                Ok(processed) => events.extend(processed),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        let deferred = self.deferred.take().unwrap_or_default();
+        result?;
+
+        for device in deferred {
+            self.udev_sender.send(device.udev(), "add")?;
+        }
+        Ok(events)
+    }
 
+    /// Send the `add` uevent of a device to the container, or defer it until the current batch
+    /// is applied.
+    fn announce(&mut self, device: &Device) -> Result<()> {
+        match &mut self.deferred {
+            Some(deferred) => deferred.push(device.clone()),
+            None => self.udev_sender.send(device.udev(), "add")?,
+        }
+        Ok(())
+    }
+
+    /// Handle a request from the command line.
+    ///
+    /// Devices are attached and detached by feeding events through the monitor, so they are
+    /// tracked like any other device afterwards.
+    async fn handle_request(
+        &mut self,
+        request: &Request,
+        events: &mut Vec<Event>,
+    ) -> Result<String> {
+        match request {
+            Request::Attach(device) => {
+                ensure!(
+                    self.locked.is_none(),
+                    "Device set of the container is locked after initialization"
+                );
+                ensure!(
+                    !self.lease_expired(),
+                    "Device lease of the container has expired"
+                );
+                let device_ref = device.parse::<cli::DeviceRef>()?;
+                let device = device_ref.device()?;
+                self.monitor
+                    .add_root(device.syspath().to_owned(), Some(device_ref))?;
+            }
+            Request::Detach(device) => {
+                let device = device.parse::<cli::DeviceRef>()?.device()?;
+                let syspath = device.syspath();
+                if self.hubs.iter().any(|hub| hub.starts_with(syspath)) {
+                    bail!(
+                        "Cannot detach {}, which contains a device configured for the container",
+                        syspath.display()
+                    );
+                }
+                self.monitor.forget(syspath);
+            }
+            Request::Lease { extend } => {
+                let warning = self.options.lease.warning;
+                let lease = self
+                    .lease
+                    .as_mut()
+                    .context("Devices of the container are not leased")?;
+                ensure!(!lease.expired, "Device lease of the container has expired");
+                let now = Instant::now();
+                if let Some(extend) = extend {
+                    lease.expiry = lease
+                        .expiry
+                        .checked_add(*extend)
+                        .context("Device lease cannot be extended that far")?;
+                    // Warn again if the warning time has been pushed back into the future.
+                    if warning.is_none_or(|warning| {
+                        now.checked_add(warning)
+                            .is_some_and(|warning| warning < lease.expiry)
+                    }) {
+                        lease.warned = false;
+                    }
+                    log::info!(
+                        "Device lease extended by {}",
+                        humantime::format_duration(*extend)
+                    );
+                }
+                return Ok(format!(
+                    "Device lease expires in {}",
+                    humantime::format_duration(round_secs(lease.expiry - now))
+                ));
+            }
+            Request::Reconcile => {
+                let corrections = self.reconcile(events).await?;
+                if corrections.is_empty() {
+                    return Ok("No drift found".to_owned());
+                }
+                return Ok(corrections.join("\n"));
+            }
+        }
+
+        while let Some(event) = self.monitor.try_read()? {
+            events.extend(self.process_and_record(event).await?);
+        }
+
+        let message: Vec<_> = events.iter().map(ToString::to_string).collect();
+        if message.is_empty() {
+            Ok("No devices changed".to_owned())
+        } else {
+            Ok(message.join("\n"))
+        }
+    }
+
+    /// Compare attached devices against the host, the device filter and the device nodes in the
+    /// container, and repair any drift.
+    ///
+    /// Returns a description of each correction made.
+    async fn reconcile(&mut self, events: &mut Vec<Event>) -> Result<Vec<String>> {
+        fn correct(corrections: &mut Vec<String>, correction: String) {
+            log::warn!("{correction}");
+            corrections.push(correction);
+        }
+        let mut corrections = Vec::new();
+
+        // Devices that have been plugged or unplugged without an event being received.
+        self.monitor.rescan()?;
+        while let Some(event) = self.monitor.try_read()? {
+            for event in self.process_and_record(event).await? {
+                corrections.push(event.to_string());
+                events.push(event);
+            }
+        }
+
+        let attached: Vec<_> = self
+            .devices
+            .values()
+            .filter(|device| device.devnode().is_some())
+            .cloned()
+            .collect();
+        let is_attached = |ty: DeviceType, devnum: (u32, u32)| {
+            attached.iter().any(|device| {
+                device
+                    .devnode()
+                    .is_some_and(|devnode| devnode.ty == ty && devnode.devnum == devnum)
+            })
+        };
+
+        for device in &attached {
+            let devnode = device.devnode().unwrap();
+            let access = self
+                .container
+                .permission(devnode.ty, devnode.devnum)
+                .await?;
+            if access.bits() != device.access.bits() {
                 self.container
-                    .device(devnode.ty, devnode.devnum, Access::all())
+                    .device(devnode.ty, devnode.devnum, device.access)
                     .await?;
+                correct(
+                    &mut corrections,
+                    format!("Restored access to device {device}"),
+                );
+            }
+        }
+        for permission in self.container.granted().await? {
+            let (ty, (major, minor)) = (permission.ty, permission.devnum);
+            let configured = self
+                .options
+                .device_rules
+                .iter()
+                .any(|rule| rule.ty == ty && rule.set == DeviceSet::Device(major, minor));
+            if !configured && !is_attached(ty, (major, minor)) {
+                self.container
+                    .device(ty, (major, minor), Access::empty())
+                    .await?;
+                correct(
+                    &mut corrections,
+                    format!("Revoked access to unattached device {major:0>3}:{minor:0>3}"),
+                );
+            }
+        }
+
+        // Only nodes of attached devices, i.e. those recorded in the journal, are repaired, as
+        // other nodes may have been provided by the runtime. A node of the wrong device at the
+        // path of an attached device is replaced.
+        let nodes = self.container.dev_nodes().await?;
+        for device in &attached {
+            let devnode = device.devnode().unwrap();
+            let present = nodes.iter().any(|(path, ty, devnum)| {
+                *path == devnode.path && *ty == devnode.ty && *devnum == devnode.devnum
+            });
+            if !present {
                 self.container
                     .mknod(&devnode.path, devnode.ty, devnode.devnum)
                     .await?;
-                for symlink in &symlinks {
+                correct(
+                    &mut corrections,
+                    format!("Recreated device node of device {device}"),
+                );
+            }
+            for symlink in &device.symlinks {
+                if self.container.read_link(symlink).await?.as_ref() != Some(&devnode.path) {
                     self.container.symlink(&devnode.path, symlink).await?;
+                    correct(
+                        &mut corrections,
+                        format!("Recreated symlink {} of device {device}", symlink.display()),
+                    );
+                }
+            }
+        }
+        Ok(corrections)
+    }
+
+    /// Reconcile devices recorded in the journal by a previous daemon against the current state.
+    ///
+    /// Devices that are still present are taken over as is, and devices that have gone away in
+    /// the meantime are cleaned up.
+    async fn restore(&mut self) -> Result<()> {
+        for entry in self.journal.take_entries() {
+            let Some(devnode) = &entry.devnode else {
+                // Network interfaces are no longer visible on the host, so look for their parent
+                // device instead.
+                let parent = entry.syspath.parent().and_then(Path::parent);
+                match parent.map(udev::Device::from_syspath) {
+                    Some(Ok(parent)) => {
+                        self.restore_device(&entry, Device::from_udev(parent))?;
+                    }
+                    _ => log::info!("Network interface gone: {}", entry.syspath.display()),
+                }
+                continue;
+            };
+
+            let device = udev::Device::from_syspath(&entry.syspath)
+                .map(Device::from_udev)
+                .ok()
+                .filter(|device| {
+                    device
+                        .devnode()
+                        .is_some_and(|current| current.devnum == devnode.devnum)
+                });
+            if let Some(device) = device {
+                self.restore_device(&entry, device)?;
+                continue;
+            }
+
+            log::info!("Device gone: {}", entry.syspath.display());
+            if let Some(mount) = &entry.mount {
+                self.container.unmount(mount).await?;
+            }
+            if devnode.ty == DeviceType::Block && !self.options.io_limits.is_empty() {
+                self.container.reset_io_limits(devnode.devnum).await?;
+            }
+            self.container
+                .device(devnode.ty, devnode.devnum, Access::empty())
+                .await?;
+            self.container
+                .owners(devnode.ty, devnode.devnum, &[], &[])
+                .await?;
+            self.container.rm(&devnode.path).await?;
+            for symlink in &entry.symlinks {
+                self.container.rm(symlink).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_device(&mut self, entry: &JournalEntry, device: Device) -> Result<()> {
+        let device = AttachedDevice {
+            device,
+            syspath: entry.syspath.clone(),
+            access: Access::from_bits_truncate(entry.access),
+            symlinks: entry.symlinks.clone(),
+            mount: entry.mount.clone(),
+            interface: entry.interface.clone(),
+            counters: None,
+        };
+        // Devices attached from the command line are not covered by the configured roots. For
+        // network interfaces, this monitors their parent on the host.
+        let host = device.device.syspath();
+        if !self.monitor.covers(host) {
+            self.monitor.add_root(host.to_owned(), None)?;
+        }
+        if device.devnode().is_some() {
+            if let Err(err) = self.claim(&device) {
+                log::warn!("Restored device {device} is in use by another container: {err:#}");
+            }
+            if let Some(group) = self.group_of(&device) {
+                let instance = cli::DeviceGroup::instance(&device);
+                self.grouped
+                    .insert(device.syspath().to_owned(), (group, instance));
+            }
+        }
+        log::info!("Restored device {device}");
+        self.journal.insert(&device)?;
+        self.devices.insert(entry.syspath.clone(), device);
+        Ok(())
+    }
+
+    async fn process_and_record(&mut self, event: DeviceEvent) -> Result<Vec<Event>> {
+        let events = self.process(event).await?;
+        for event in &events {
+            match event {
+                Event::Attach(device) => self.journal.insert(device)?,
+                Event::Detach(device) => self.journal.remove(device.syspath())?,
+                Event::Change(device) => self.journal.insert(device)?,
+                Event::Move { from, device } => {
+                    self.journal.remove(from)?;
+                    self.journal.insert(device)?;
+                }
+                _ => (),
+            }
+        }
+        Ok(events)
+    }
+
+    async fn process(&mut self, event: DeviceEvent) -> Result<Vec<Event>> {
+        match event {
+            DeviceEvent::Add(device) => {
+                if self.devices.contains_key(device.syspath()) {
+                    // Already attached, e.g. restored from the journal.
+                    return Ok(Vec::new());
+                }
+
+                if self.lease_expired() {
+                    log::warn!("Refusing device plugged after lease expired: {device}");
+                    return Ok(Vec::new());
+                }
+
+                if let Some(locked) = &self.locked {
+                    let replug = self.options.lock_after_init == cli::LockAfterInit::AllowReplug
+                        && locked.contains(&identity(&device));
+                    if !replug {
+                        log::warn!("Refusing device plugged after initialization: {device}");
+                        return Ok(Vec::new());
+                    }
                 }
 
-                self.udev_sender.send(device.udev(), "add")?;
+                if device.devnode().is_none() {
+                    return self.add_interface(device).await;
+                }
 
-                let syspath = device.syspath().to_owned();
-                let device = AttachedDevice { device, symlinks };
-                self.devices.insert(syspath, device.clone());
+                if let Some(group) = self.group_of(&device) {
+                    return self.hold(group, device).await;
+                }
 
-                Ok(Some(Event::Attach(device)))
+                let device = self.attach(device, true).await?;
+                Ok(device.map(Event::Attach).into_iter().collect())
             }
+            DeviceEvent::RootMoved { from, to } => {
+                for hub in &mut self.hubs {
+                    if *hub == from {
+                        hub.clone_from(&to);
+                    }
+                }
+                Ok(vec![Event::RootMoved { from, to }])
+            }
+            DeviceEvent::Change(device) => self.update(device, "change").await,
+            DeviceEvent::Bind(device) => self.update(device, "bind").await,
+            DeviceEvent::Unbind(device) => self.update(device, "unbind").await,
+            DeviceEvent::Move { from, device } => self.relocate(from, device).await,
             DeviceEvent::Remove(device) => {
+                for held in &mut self.held {
+                    for members in held.values_mut() {
+                        members.retain(|held| held.syspath() != device.syspath());
+                    }
+                    held.retain(|_, members| !members.is_empty());
+                }
+
+                // Network interfaces moved into the container are no longer visible to udev on the
+                // host, so their removal can only be inferred from the removal of their parents.
+                let interfaces: Vec<_> = self
+                    .devices
+                    .iter()
+                    .filter(|(syspath, attached)| {
+                        attached.interface.is_some()
+                            && *syspath != device.syspath()
+                            && syspath.starts_with(device.syspath())
+                    })
+                    .map(|(syspath, _)| syspath.clone())
+                    .collect();
+                // Interfaces of an unplugged device are gone with it, otherwise the device has been
+                // detached and its interfaces are still in the container.
+                let unplugged = !device.syspath().exists();
+                let mut events = Vec::new();
+                for syspath in interfaces {
+                    let device = self.devices.remove(&syspath).unwrap();
+                    if !unplugged {
+                        if let Err(err) = self.release_interface(&device).await {
+                            log::warn!("Cannot move {device} out of the container: {err:#}");
+                        }
+                    }
+                    self.udev_sender.send(device.udev(), "remove")?;
+                    events.push(Event::Detach(device));
+                }
+
                 let Some(device) = self.devices.remove(device.syspath()) else {
-                    return Ok(None);
+                    return Ok(events);
                 };
 
-                let devnode = device.devnode().unwrap();
-                self.container
-                    .device(devnode.ty, devnode.devnum, Access::empty())
-                    .await?;
-                self.container.rm(&devnode.path).await?;
-                for symlink in &device.symlinks {
-                    self.container.rm(symlink).await?;
+                let Some(devnode) = device.devnode().cloned() else {
+                    // Moving an interface to another namespace removes it from the host, which is
+                    // what we observe here. It is still attached to the container.
+                    self.devices.insert(device.syspath().to_owned(), device);
+                    return Ok(events);
+                };
+
+                let group = self.grouped.remove(device.syspath());
+                let device = self.revoke(device, &devnode, true).await?;
+                events.push(Event::Detach(device));
+
+                // The rest of the group instance goes with it, and is held back until the instance
+                // is complete again.
+                if let Some(group) = group {
+                    let members: Vec<_> = self
+                        .grouped
+                        .iter()
+                        .filter(|(_, member)| **member == group)
+                        .map(|(syspath, _)| syspath.clone())
+                        .collect();
+                    let (group, instance) = group;
+                    for syspath in members {
+                        let Some(member) = self.devices.remove(&syspath) else {
+                            continue;
+                        };
+                        let devnode = member.devnode().cloned().unwrap();
+                        let member = self.revoke(member, &devnode, true).await?;
+                        self.held[group]
+                            .entry(instance.clone())
+                            .or_default()
+                            .push(member.device.clone());
+                        events.push(Event::Detach(member));
+                    }
                 }
+                Ok(events)
+            }
+        }
+    }
 
-                self.udev_sender.send(device.udev(), "remove")?;
+    /// Refresh an attached device after its properties or driver have changed, and forward the
+    /// event to the container.
+    async fn update(&mut self, device: Device, action: &str) -> Result<Vec<Event>> {
+        let Some(mut attached) = self.devices.get(device.syspath()).cloned() else {
+            // A block device left out by the block access policy may be covered now, e.g. after
+            // its partition has been renamed.
+            let block = device
+                .devnode()
+                .is_some_and(|devnode| devnode.ty == DeviceType::Block);
+            let held = self.held.iter().any(|held| {
+                held.values()
+                    .flatten()
+                    .any(|member| member.syspath() == device.syspath())
+            });
+            if block
+                && !held
+                && cli::BlockAccess::access(&self.options.block_access, &device).is_some()
+            {
+                return Box::pin(self.process(DeviceEvent::Add(device))).await;
+            }
+            return Ok(Vec::new());
+        };
+        attached.device = device;
 
-                Ok(Some(Event::Detach(device)))
+        // Block access policies may depend on properties, e.g. partition names.
+        if let Some(devnode) = attached.devnode().cloned() {
+            match cli::BlockAccess::access(&self.options.block_access, &attached) {
+                None => {
+                    log::info!("Device no longer exposed by block access policy: {attached}");
+                    self.devices.remove(attached.syspath());
+                    let device = self.revoke(attached, &devnode, true).await?;
+                    return Ok(vec![Event::Detach(device)]);
+                }
+                Some(access) if access.bits() != attached.access.bits() => {
+                    self.container
+                        .device(devnode.ty, devnode.devnum, access)
+                        .await?;
+                    attached.access = access;
+                }
+                Some(_) => (),
             }
         }
+
+        self.update_symlinks(&mut attached, false).await?;
+        self.udev_sender.send(attached.udev(), action)?;
+        self.devices
+            .insert(attached.syspath().to_owned(), attached.clone());
+        Ok(vec![Event::Change(attached)])
+    }
+
+    /// Follow an attached device that has been renamed, recreating its device node if the name
+    /// of the node has changed.
+    async fn relocate(&mut self, from: PathBuf, device: Device) -> Result<Vec<Event>> {
+        let Some(mut attached) = self.devices.remove(&from) else {
+            return Ok(Vec::new());
+        };
+        let old = attached.devnode().cloned();
+        attached.syspath = device.syspath().to_owned();
+        attached.device = device;
+        if let Some(group) = self.grouped.remove(&from) {
+            self.grouped.insert(attached.syspath.clone(), group);
+        }
+
+        let mut relink = false;
+        if let (Some(old), Some(new)) = (old, attached.devnode().cloned()) {
+            if old.path != new.path {
+                self.container.rm(&old.path).await?;
+                self.container.mknod(&new.path, new.ty, new.devnum).await?;
+                relink = true;
+            }
+            // Claims are keyed by syspath.
+            self.claims.remove(&from);
+            if let Err(err) = self.claim(&attached) {
+                log::warn!("Moved device {attached} is in use by another container: {err:#}");
+            }
+        }
+        self.update_symlinks(&mut attached, relink).await?;
+
+        self.udev_sender.send(attached.udev(), "move")?;
+        self.devices
+            .insert(attached.syspath().to_owned(), attached.clone());
+        Ok(vec![Event::Move {
+            from,
+            device: attached,
+        }])
+    }
+
+    /// Bring symlinks of an attached device in line with its current properties. If `relink` is
+    /// set, all symlinks are recreated, e.g. because the device node has been renamed.
+    async fn update_symlinks(&mut self, device: &mut AttachedDevice, relink: bool) -> Result<()> {
+        let Some(devnode) = device.devnode().cloned() else {
+            return Ok(());
+        };
+        let symlinks: Vec<_> = self
+            .options
+            .symlinks
+            .iter()
+            .filter_map(|dev| dev.matches(device))
+            .collect();
+        for symlink in &device.symlinks {
+            if relink || !symlinks.contains(symlink) {
+                self.container.rm(symlink).await?;
+            }
+        }
+        for symlink in &symlinks {
+            if relink || !device.symlinks.contains(symlink) {
+                self.container.symlink(&devnode.path, symlink).await?;
+            }
+        }
+        device.symlinks = symlinks;
+        Ok(())
+    }
+
+    fn group_of(&self, device: &Device) -> Option<usize> {
+        self.options
+            .groups
+            .iter()
+            .position(|group| group.matches(device))
+    }
+
+    /// Hold back a member of a device group until all members of its group instance are present,
+    /// then attach them all.
+    ///
+    /// If any member cannot be attached, the members attached so far are rolled back and the
+    /// group instance stays held back.
+    async fn hold(&mut self, group: usize, device: Device) -> Result<Vec<Event>> {
+        let name = self.options.groups[group].name.clone();
+        let instance = cli::DeviceGroup::instance(&device);
+        let held = self.held[group].entry(instance.clone()).or_default();
+        held.push(device);
+        if !self.options.groups[group].is_complete(held.iter()) {
+            log::info!(
+                "Holding back devices of group {name} on {} until all members are present",
+                instance.display()
+            );
+            return Ok(Vec::new());
+        }
+
+        let members = self.held[group].remove(&instance).unwrap_or_default();
+        let mut attached = Vec::new();
+        for member in &members {
+            let error = match self.attach(member.clone(), false).await {
+                Ok(Some(device)) => {
+                    attached.push(device);
+                    continue;
+                }
+                Ok(None) => anyhow::anyhow!("{member} is not exposed"),
+                Err(err) => {
+                    self.abandon(member).await;
+                    err
+                }
+            };
+            log::warn!(
+                "Cannot attach group {name} on {}, rolling back: {error:#}",
+                instance.display()
+            );
+            for device in attached.into_iter().rev() {
+                self.devices.remove(device.syspath());
+                let devnode = device.devnode().cloned().unwrap();
+                self.revoke(device, &devnode, false).await?;
+            }
+            self.held[group].insert(instance, members);
+            return Ok(Vec::new());
+        }
+
+        // Only announce the devices once all of them are in place.
+        for device in &attached {
+            self.grouped
+                .insert(device.syspath().to_owned(), (group, instance.clone()));
+            self.announce(device)?;
+        }
+        Ok(attached.into_iter().map(Event::Attach).collect())
+    }
+
+    /// Best-effort cleanup of a device that failed to attach halfway.
+    async fn abandon(&mut self, device: &Device) {
+        let Some(devnode) = device.devnode() else {
+            return;
+        };
+        let _ = self
+            .container
+            .device(devnode.ty, devnode.devnum, Access::empty())
+            .await;
+        let _ = self
+            .container
+            .owners(devnode.ty, devnode.devnum, &[], &[])
+            .await;
+        let _ = self.container.rm(&devnode.path).await;
+        for symlink in self
+            .options
+            .symlinks
+            .iter()
+            .filter_map(|s| s.matches(device))
+        {
+            let _ = self.container.rm(&symlink).await;
+        }
+        self.claims.remove(device.syspath());
+    }
+
+    /// Attach a device node to the container, unless policies say otherwise.
+    ///
+    /// The `add` uevent is only sent if `announce` is set.
+    async fn attach(&mut self, device: Device, announce: bool) -> Result<Option<AttachedDevice>> {
+        let devnode = device.devnode().context("Device has no device node")?;
+
+        let Some(access) = cli::BlockAccess::access(&self.options.block_access, &device) else {
+            log::info!("Device not exposed by block access policy: {device}");
+            return Ok(None);
+        };
+
+        if let Err(err) = self.claim(&device) {
+            log::warn!("Not attaching device {device}: {err:#}");
+            return Ok(None);
+        }
+
+        let symlinks: Vec<_> = self
+            .options
+            .symlinks
+            .iter()
+            .filter_map(|dev| dev.matches(&device))
+            .collect();
+
+        // Device numbers are reused, so counters may belong to a device attached before.
+        self.container
+            .reset_counters(devnode.ty, devnode.devnum)
+            .await?;
+
+        // Restrict the device before granting access, so that it is never accessible to
+        // other users.
+        if let Some(owner) = self.options.owners.iter().find(|o| o.matches(&device)) {
+            self.container
+                .owners(devnode.ty, devnode.devnum, &owner.uids, &owner.gids)
+                .await?;
+        }
+        self.container
+            .device(devnode.ty, devnode.devnum, access)
+            .await?;
+        self.container
+            .mknod(&devnode.path, devnode.ty, devnode.devnum)
+            .await?;
+        for symlink in &symlinks {
+            self.container.symlink(&devnode.path, symlink).await?;
+        }
+
+        if self.has_io_limits(&device) {
+            if let Err(err) = self
+                .container
+                .set_io_limits(devnode.devnum, &self.options.io_limits)
+                .await
+            {
+                log::warn!("Cannot limit IO of {}: {:?}", devnode.path.display(), err);
+            }
+        }
+
+        let mut mount = None;
+        if let Some((policy, target)) = self
+            .options
+            .mounts
+            .iter()
+            .find_map(|policy| Some((policy, policy.matches(&device)?)))
+        {
+            // The filesystem is mounted from the host, so device access policy must be
+            // enforced here.
+            let policy = if access.contains(Access::WRITE) {
+                policy.clone()
+            } else {
+                policy.read_only()
+            };
+            // Mounts are not stacked, as the lower one could then never be unmounted on its own.
+            let taken = self
+                .devices
+                .values()
+                .any(|attached| attached.mount.as_ref() == Some(&target));
+            // A filesystem that fails to mount should not prevent the device itself from
+            // being used.
+            if taken {
+                log::warn!(
+                    "Cannot mount {}: {} is already in use by another device",
+                    devnode.path.display(),
+                    target.display()
+                );
+            } else {
+                match self.container.mount(&devnode.path, &policy, &target).await {
+                    Ok(()) => mount = Some(target),
+                    Err(err) => {
+                        log::warn!("Cannot mount {}: {:?}", devnode.path.display(), err)
+                    }
+                }
+            }
+        }
+
+        if announce {
+            self.announce(&device)?;
+        }
+
+        let syspath = device.syspath().to_owned();
+        let device = AttachedDevice {
+            device,
+            syspath: syspath.clone(),
+            access,
+            symlinks,
+            mount,
+            interface: None,
+            counters: None,
+        };
+        self.devices.insert(syspath, device.clone());
+
+        Ok(Some(device))
+    }
+
+    /// Revoke access to a device node and remove it from the container.
+    ///
+    /// The `remove` uevent is only sent if `announce` is set.
+    async fn revoke(
+        &mut self,
+        mut device: AttachedDevice,
+        devnode: &DevNode,
+        announce: bool,
+    ) -> Result<AttachedDevice> {
+        // Counters are informational only, so failing to read them must not keep the device
+        // accessible.
+        device.counters = match self.container.counters(devnode.ty, devnode.devnum).await {
+            Ok(counters) => Some(counters),
+            Err(err) => {
+                log::warn!("Cannot read access counters of {device}: {err:#}");
+                None
+            }
+        };
+
+        if let Some(mount) = &device.mount {
+            self.container.unmount(mount).await?;
+        }
+        if self.has_io_limits(&device) {
+            self.container.reset_io_limits(devnode.devnum).await?;
+        }
+        self.container
+            .device(devnode.ty, devnode.devnum, Access::empty())
+            .await?;
+        self.container
+            .owners(devnode.ty, devnode.devnum, &[], &[])
+            .await?;
+        self.container.rm(&devnode.path).await?;
+        for symlink in &device.symlinks {
+            self.container.rm(symlink).await?;
+        }
+
+        if announce {
+            self.udev_sender.send(device.udev(), "remove")?;
+        }
+        self.claims.remove(device.syspath());
+        self.grouped.remove(device.syspath());
+        Ok(device)
+    }
+
+    /// Claim a device for the container, so that it is not attached to other containers.
+    fn claim(&mut self, device: &Device) -> Result<()> {
+        let shared = self.options.shared.iter().any(|dev| dev.matches(device));
+        let claim = Claim::acquire(device.syspath(), self.container.id(), shared)?;
+        self.claims.insert(device.syspath().to_owned(), claim);
+        Ok(())
+    }
+
+    /// Handle the lease reaching its warning time or expiring.
+    async fn lease_timeout(&mut self) -> Result<Vec<Event>> {
+        let Some(lease) = &mut self.lease else {
+            return Ok(Vec::new());
+        };
+
+        let now = Instant::now();
+        if now < lease.expiry {
+            lease.warned = true;
+            let remaining = round_secs(lease.expiry - now);
+            log::info!(
+                "Device lease expires in {}",
+                humantime::format_duration(remaining)
+            );
+            // Marked so that the container can tell the warning apart from a real change.
+            let remaining = remaining.as_secs().to_string();
+            for device in self.devices.values() {
+                self.udev_sender.send_with(
+                    device.udev(),
+                    "change",
+                    &[("LEASE_EXPIRES_IN", &remaining)],
+                )?;
+            }
+            return Ok(Vec::new());
+        }
+
+        lease.expired = true;
+        let mut syspaths: Vec<_> = self.devices.keys().cloned().collect();
+        // Children before parents, as if they are unplugged.
+        syspaths.sort_by_key(|syspath| std::cmp::Reverse(syspath.components().count()));
+        let mut events = Vec::new();
+        for syspath in syspaths {
+            let Some(devnode) = self.devices[&syspath].devnode().cloned() else {
+                if let Err(err) = self.release_interface(&self.devices[&syspath]).await {
+                    log::warn!(
+                        "Cannot move {} out of the container: {err:#}",
+                        self.devices[&syspath]
+                    );
+                    continue;
+                }
+                let device = self.devices.remove(&syspath).unwrap();
+                self.udev_sender.send(device.udev(), "remove")?;
+                self.journal.remove(device.syspath())?;
+                events.push(Event::LeaseExpired(device));
+                continue;
+            };
+            let device = self.devices.remove(&syspath).unwrap();
+            let device = self.revoke(device, &devnode, true).await?;
+            self.journal.remove(device.syspath())?;
+            events.push(Event::LeaseExpired(device));
+        }
+        Ok(events)
+    }
+
+    /// When the lease needs attention next.
+    fn lease_timer(&self) -> Option<Instant> {
+        let lease = self.lease.as_ref().filter(|lease| !lease.expired)?;
+        match self.options.lease.warning {
+            Some(warning) if !lease.warned => lease.expiry.checked_sub(warning),
+            _ => Some(lease.expiry),
+        }
+    }
+
+    fn lease_expired(&self) -> bool {
+        self.lease.as_ref().is_some_and(|lease| lease.expired)
+    }
+
+    /// IO limits only apply to whole disks.
+    fn has_io_limits(&self, device: &Device) -> bool {
+        !self.options.io_limits.is_empty()
+            && device
+                .devnode()
+                .is_some_and(|devnode| devnode.ty == DeviceType::Block)
+            && device.udev().devtype().is_some_and(|ty| ty == "disk")
+    }
+
+    /// Move a network interface attached to the container back to the host, under its original
+    /// name.
+    async fn release_interface(&self, device: &AttachedDevice) -> Result<()> {
+        let name = device
+            .interface
+            .as_deref()
+            .context("Device is not a network interface")?;
+        let host_name = device
+            .syspath()
+            .file_name()
+            .and_then(OsStr::to_str)
+            .context("Network interface has no name")?;
+        self.container.release_interface(name, host_name).await
+    }
+
+    /// Move a network interface into the container.
+    async fn add_interface(&mut self, device: Device) -> Result<Vec<Event>> {
+        if device.udev().subsystem().and_then(OsStr::to_str) != Some("net") {
+            return Ok(Vec::new());
+        }
+
+        let index: u32 = device
+            .udev()
+            .property_value("IFINDEX")
+            .and_then(OsStr::to_str)
+            .context("Network interface has no IFINDEX")?
+            .parse()?;
+        let rename = self
+            .options
+            .interfaces
+            .iter()
+            .find_map(|interface| interface.matches(&device));
+
+        // An interface that cannot be moved, e.g. because its new name is taken, should not bring
+        // down the daemon.
+        if let Err(err) = self
+            .container
+            .move_interface(index, rename.as_deref())
+            .await
+        {
+            log::warn!("Cannot move {device} into the container: {err:#}");
+            return Ok(Vec::new());
+        }
+
+        self.announce(&device)?;
+
+        let syspath = device.syspath().to_owned();
+        let interface = rename.or_else(|| Some(device.udev().sysname().to_str()?.to_owned()));
+        let device = AttachedDevice {
+            device,
+            syspath: syspath.clone(),
+            access: Access::empty(),
+            symlinks: Vec::new(),
+            mount: None,
+            interface,
+            counters: None,
+        };
+        self.devices.insert(syspath, device.clone());
+
+        Ok(vec![Event::Attach(device)])
     }
 }
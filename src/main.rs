@@ -1,34 +1,50 @@
 mod cgroup;
 mod cli;
 mod dev;
+mod gc;
 mod hotplug;
 mod runc;
 mod util;
 
-use cli::{DeviceRef, Symlink};
-use hotplug::{AttachedDevice, HotPlug};
+use cli::{DeviceRef, RootUnplug};
+use hotplug::{AttachedDevice, DeniedAccess, HotPlug};
 
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::File;
 use std::io::{PipeWriter, Read};
 use std::mem::ManuallyDrop;
+use std::os::fd::AsFd;
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::pin::pin;
 use std::process::ExitCode;
 use std::sync::Arc;
 
 use anyhow::{Context, Result, bail};
 use clap::Parser;
-use log::info;
+use log::{info, warn};
 use runc::Container;
 use runc::cli::{CreateOptions, GlobalOptions};
 use rustix::process::Signal;
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
 
 #[derive(Clone)]
 enum Event {
     Attach(AttachedDevice),
     Detach(AttachedDevice),
+    Denied(DeniedAccess),
+    LeaseExpired(AttachedDevice),
+    RootMoved {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Change(AttachedDevice),
+    Move {
+        from: PathBuf,
+        device: AttachedDevice,
+    },
     Initialized,
     Stopped,
 }
@@ -42,6 +58,26 @@ impl Display for Event {
             Event::Detach(dev) => {
                 write!(f, "Detaching device {dev}")
             }
+            Event::Denied(access) => {
+                write!(f, "{access}")
+            }
+            Event::LeaseExpired(dev) => {
+                write!(f, "Lease expired, detaching device {dev}")
+            }
+            Event::RootMoved { from, to } => {
+                write!(
+                    f,
+                    "Hub device moved from {} to {}",
+                    from.display(),
+                    to.display()
+                )
+            }
+            Event::Change(dev) => {
+                write!(f, "Updating device {dev}")
+            }
+            Event::Move { from, device } => {
+                write!(f, "Moving device {} to {device}", from.display())
+            }
             Event::Initialized => {
                 write!(f, "Container initialized")
             }
@@ -52,10 +88,10 @@ impl Display for Event {
     }
 }
 
-async fn create(global: GlobalOptions, create: CreateOptions, notifier: PipeWriter) -> Result<()> {
-    let mut notifier = Some(notifier);
-
-    let config = runc::config::Config::from_bundle(&create.bundle)?;
+/// Parse root devices and hotplug options from annotations.
+fn hotplug_options(
+    config: &runc::config::Config,
+) -> Result<(Vec<(DeviceRef, PathBuf)>, hotplug::Options)> {
     let mut devices = Vec::new();
     let device_annotation = config
         .annotations
@@ -66,15 +102,46 @@ async fn create(global: GlobalOptions, create: CreateOptions, notifier: PipeWrit
         )?;
     for device in device_annotation.split(',') {
         let devref: DeviceRef = device.parse()?;
-        devices.push(devref.device()?.syspath().to_owned());
+        let syspath = devref.device()?.syspath().to_owned();
+        devices.push((devref, syspath));
     }
 
-    let mut symlinks = Vec::<Symlink>::new();
-    if let Some(symlink_annotation) = config.annotations.get("org.lowrisc.hotplug.symlinks") {
-        for symlink in symlink_annotation.split(',') {
-            symlinks.push(symlink.parse()?);
+    let mut groups = Vec::new();
+    for key in config.annotations.keys() {
+        if let Some(name) = key.strip_prefix("org.lowrisc.hotplug.group.") {
+            groups.push(cli::DeviceGroup::new(
+                name.to_owned(),
+                config.annotation_list(key)?,
+            )?);
         }
     }
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let options = hotplug::Options {
+        symlinks: config.annotation_list("org.lowrisc.hotplug.symlinks")?,
+        mounts: config.annotation_list("org.lowrisc.hotplug.mounts")?,
+        interfaces: config.annotation_list("org.lowrisc.hotplug.interfaces")?,
+        block_access: config.annotation_list("org.lowrisc.hotplug.block-access")?,
+        io_limits: config.annotation_list("org.lowrisc.hotplug.io")?,
+        device_rules: config.annotation_list("org.lowrisc.hotplug.device-cgroup-rules")?,
+        owners: config.annotation_list("org.lowrisc.hotplug.owners")?,
+        lock_after_init: config.annotation("org.lowrisc.hotplug.lock-after-init")?,
+        lease: config.annotation("org.lowrisc.hotplug.lease")?,
+        shared: config.annotation_list("org.lowrisc.hotplug.shared")?,
+        root_unplug: config.annotation("org.lowrisc.hotplug.root-unplug")?,
+        required: config.annotation_list("org.lowrisc.hotplug.required")?,
+        required_timeout: config.annotation("org.lowrisc.hotplug.required-timeout")?,
+        groups,
+        settle: config.annotation("org.lowrisc.hotplug.settle")?,
+        reconcile_interval: config.annotation("org.lowrisc.hotplug.reconcile-interval")?,
+    };
+
+    Ok((devices, options))
+}
+
+async fn create(global: GlobalOptions, create: CreateOptions, notifier: PipeWriter) -> Result<()> {
+    let config = runc::config::Config::from_bundle(&create.bundle)?;
+    let (devices, options) = hotplug_options(&config)?;
 
     // Switch the logger to syslog. The runc logs are barely forwarded to the user or syslog by
     // container managers and orchestrators, while we do want to preserve the hotplug events.
@@ -93,11 +160,43 @@ async fn create(global: GlobalOptions, create: CreateOptions, notifier: PipeWrit
 
     let state: runc::state::State =
         runc::state::State::from_root_and_id(&global.root, &create.container_id)?;
+    let journal = hotplug::Journal::create(&state.id)?;
 
     // Create a container handler.
     // To avoid race where the container is deleted before the daemon is started, do this
     // before forking.
     let container = Arc::new(Container::new(&config, &state)?);
+
+    run(container, devices, options, journal, notifier).await
+}
+
+async fn reattach(global: GlobalOptions, id: String, notifier: impl AsFd) -> Result<()> {
+    let state = runc::state::State::from_root_and_id(&global.root, &id)?;
+    let bundle = state
+        .bundle()
+        .context("Cannot find the bundle of the container")?;
+    let config = runc::config::Config::from_bundle(bundle)?;
+    let (devices, options) = hotplug_options(&config)?;
+
+    // This fails if another daemon is still attached to the container.
+    let journal = hotplug::Journal::open(&state.id)?;
+
+    util::log::global_replace(Box::new(util::log::SyslogLogger::new()?));
+
+    let container = Arc::new(Container::reattach(&config, &state)?);
+
+    run(container, devices, options, journal, notifier).await
+}
+
+async fn run(
+    container: Arc<Container>,
+    devices: Vec<(DeviceRef, PathBuf)>,
+    options: hotplug::Options,
+    journal: hotplug::Journal,
+    notifier: impl AsFd,
+) -> Result<()> {
+    let mut notifier = Some(notifier);
+
     // Prevent the container's destructor from being executed in abnormal exit.
     let container_keep = ManuallyDrop::new(container.clone());
 
@@ -108,7 +207,9 @@ async fn create(global: GlobalOptions, create: CreateOptions, notifier: PipeWrit
     rustix::stdio::dup2_stdout(&null)?;
     rustix::stdio::dup2_stderr(null)?;
 
-    let mut hotplug = HotPlug::new(Arc::clone(&container), devices.clone(), symlinks)?;
+    let root_unplug = options.root_unplug;
+    let mut hubs: Vec<PathBuf> = devices.iter().map(|(_, path)| path.clone()).collect();
+    let mut hotplug = HotPlug::new(Arc::clone(&container), devices, options, journal)?;
     let hotplug_stream = hotplug.run();
 
     let container_stream = {
@@ -119,14 +220,42 @@ async fn create(global: GlobalOptions, create: CreateOptions, notifier: PipeWrit
         }
     };
 
+    let audit_stream = hotplug::audit(container.clone());
+
     let mut stream = pin!(
         tokio_stream::empty()
             .merge(hotplug_stream)
             .merge(container_stream)
+            .merge(audit_stream)
     );
 
+    // Unplugged root devices that are waited for, with the time to give up waiting.
+    let mut unplugged: HashMap<PathBuf, Option<Instant>> = HashMap::new();
+
     loop {
-        let event = stream.try_next().await?.context("No more events")?;
+        let deadline = unplugged.values().flatten().min().copied();
+        let event = tokio::select! {
+            event = stream.try_next() => {
+                match event.and_then(|event| event.context("No more events")) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        if let Some(notifier) = notifier.take() {
+                            // Fail `runc create` with the reason, rather than just the exit code.
+                            let _ = rustix::io::write(notifier, format!("\x01{err:#}").as_bytes());
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() =>
+            {
+                info!("Hub device did not come back in time. Stopping container.");
+                let _ = container.kill(Signal::KILL).await;
+                container.wait().await?;
+                break;
+            }
+        };
         info!("{}", event);
         match event {
             Event::Initialized => {
@@ -134,11 +263,53 @@ async fn create(global: GlobalOptions, create: CreateOptions, notifier: PipeWrit
                 let notifier = notifier.take().context("Initialized event seen twice")?;
                 rustix::io::write(notifier, &[0])?;
             }
-            Event::Detach(dev) if devices.iter().any(|hub| dev.syspath() == hub) => {
-                info!("Hub device detached. Stopping container.");
-                let _ = container.kill(Signal::KILL).await;
-                container.wait().await?;
-                break;
+            Event::Detach(dev) if hubs.iter().any(|hub| dev.syspath() == hub) => {
+                match root_unplug {
+                    RootUnplug::Kill => {
+                        info!("Hub device detached. Stopping container.");
+                        let _ = container.kill(Signal::KILL).await;
+                        container.wait().await?;
+                        break;
+                    }
+                    RootUnplug::Signal {
+                        name,
+                        signal,
+                        grace,
+                    } => {
+                        info!("Hub device detached. Sending SIG{name} to container.");
+                        let _ = container.kill(signal).await;
+                        match tokio::time::timeout(grace, container.wait()).await {
+                            Ok(result) => result?,
+                            Err(_) => {
+                                info!("Container did not stop in time. Stopping container.");
+                                let _ = container.kill(Signal::KILL).await;
+                                container.wait().await?;
+                            }
+                        }
+                        break;
+                    }
+                    RootUnplug::Wait { timeout } => {
+                        info!("Hub device detached. Waiting for it to come back.");
+                        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                        unplugged.insert(dev.syspath().to_owned(), deadline);
+                    }
+                    RootUnplug::Ignore => {
+                        info!("Hub device detached. Keeping container running.");
+                    }
+                }
+            }
+            Event::Attach(dev) if unplugged.remove(dev.syspath()).is_some() => {
+                info!("Hub device came back.");
+            }
+            Event::RootMoved { from, to } => {
+                for hub in &mut hubs {
+                    if *hub == from {
+                        hub.clone_from(&to);
+                    }
+                }
+                if unplugged.remove(&from).is_some() {
+                    info!("Hub device came back.");
+                }
             }
             Event::Stopped => {
                 break;
@@ -147,6 +318,18 @@ async fn create(global: GlobalOptions, create: CreateOptions, notifier: PipeWrit
         }
     }
 
+    // The usage summary is informational, so failing to produce it must not skip the cleanup.
+    match hotplug::usage(&container).await {
+        Ok(usages) => {
+            info!("Device usage summary:");
+            for usage in usages {
+                info!("  {usage}");
+            }
+        }
+        Err(err) => warn!("Cannot summarize device usage: {err:#}"),
+    }
+
+    hotplug::Journal::discard(container.id());
     drop(ManuallyDrop::into_inner(container_keep));
 
     Ok(())
@@ -182,6 +365,12 @@ fn do_main() -> Result<()> {
     // itself monitors the state of the container and exits when the cgroup is removed.
     // Therefore, we only need to intercept "create" command, and the rest can be forwarded
     // to runc directly.
+    //
+    // The "attach" and "detach" verbs (which are our own) talk to the daemon over its control
+    // socket, so that the daemon remains the only one managing the container's devices.
+    //
+    // Should the daemon die, the "reattach" verb (which is our own) starts a new daemon for
+    // the existing container.
 
     let args = runc::cli::Command::parse();
 
@@ -206,8 +395,48 @@ fn do_main() -> Result<()> {
         }
     }
 
-    let create_options = match args.command {
-        runc::cli::Subcommand::Create(create_options) => create_options,
+    enum Daemon {
+        Create(CreateOptions),
+        Reattach(String),
+    }
+
+    let daemon = match args.command {
+        runc::cli::Subcommand::Create(create_options) => Daemon::Create(create_options),
+        runc::cli::Subcommand::Reattach { container_id } => Daemon::Reattach(container_id),
+        runc::cli::Subcommand::Gc { dry_run } => return gc::gc(&args.global, dry_run),
+        runc::cli::Subcommand::Attach {
+            container_id,
+            device,
+        } => {
+            device.parse::<DeviceRef>()?;
+            let request = hotplug::ControlRequest::Attach(device);
+            println!("{}", hotplug::request(&container_id, &request)?);
+            return Ok(());
+        }
+        runc::cli::Subcommand::Lease {
+            container_id,
+            extend,
+        } => {
+            let request = hotplug::ControlRequest::Lease {
+                extend: extend.map(Into::into),
+            };
+            println!("{}", hotplug::request(&container_id, &request)?);
+            return Ok(());
+        }
+        runc::cli::Subcommand::Reconcile { container_id } => {
+            let request = hotplug::ControlRequest::Reconcile;
+            println!("{}", hotplug::request(&container_id, &request)?);
+            return Ok(());
+        }
+        runc::cli::Subcommand::Detach {
+            container_id,
+            device,
+        } => {
+            device.parse::<DeviceRef>()?;
+            let request = hotplug::ControlRequest::Detach(device);
+            println!("{}", hotplug::request(&container_id, &request)?);
+            return Ok(());
+        }
         runc::cli::Subcommand::Run { .. } => {
             // `run` is a shorthand for `create` and `start`.
             // It is never used by containerd shims, so there is no need to support it.
@@ -229,14 +458,27 @@ fn do_main() -> Result<()> {
     match safe_fork::fork().expect("should still be single-threaded") {
         None => {
             drop(parent);
-            tokio::runtime::Runtime::new()?.block_on(create(args.global, create_options, child))?;
+            let runtime = tokio::runtime::Runtime::new()?;
+            match daemon {
+                Daemon::Create(create_options) => {
+                    runtime.block_on(create(args.global, create_options, child))?
+                }
+                Daemon::Reattach(id) => runtime.block_on(reattach(args.global, id, child))?,
+            }
         }
         Some(pid) => {
             drop(child);
-            if parent.read(&mut [0])? == 0 {
+            let mut status = [0];
+            if parent.read(&mut status)? == 0 {
                 // In this case, the child process exited before notifying us.
                 std::process::exit(pid.join()?.code().unwrap_or(1) as _);
             }
+            if status[0] != 0 {
+                // The child process failed before initialization and told us why.
+                let mut message = String::new();
+                parent.read_to_string(&mut message)?;
+                bail!("{message}");
+            }
         }
     }
 
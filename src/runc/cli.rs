@@ -43,6 +43,49 @@ pub enum Subcommand {
     // We need to be able to parse the rest (hence `trailing_var_arg` and `external_subcommand`) without error, but
     // we don't make use of these and forward to runc directly.
     Create(CreateOptions),
+    /// Start a new daemon for a running container, e.g. after the previous one has crashed.
+    ///
+    /// This is not a runc subcommand.
+    Reattach {
+        container_id: String,
+    },
+    /// Attach a device and its children to a running container.
+    ///
+    /// This is not a runc subcommand.
+    Attach {
+        container_id: String,
+        device: String,
+    },
+    /// Detach a device and its children from a running container.
+    ///
+    /// This is not a runc subcommand.
+    Detach {
+        container_id: String,
+        device: String,
+    },
+    /// Show or extend the device lease of a running container.
+    ///
+    /// This is not a runc subcommand.
+    Lease {
+        container_id: String,
+        /// Extend the lease by this duration, e.g. `1h`.
+        #[arg(long)]
+        extend: Option<humantime::Duration>,
+    },
+    /// Reconcile devices of a running container against the host, repairing any drift.
+    ///
+    /// This is not a runc subcommand.
+    Reconcile {
+        container_id: String,
+    },
+    /// Remove state left behind by daemons that did not exit cleanly.
+    ///
+    /// This is not a runc subcommand.
+    Gc {
+        /// Only list what would be removed.
+        #[arg(long)]
+        dry_run: bool,
+    },
     Run {
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
@@ -1,9 +1,12 @@
 use std::collections::HashMap;
 use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use serde::Deserialize;
 
+use crate::cgroup::{Access, DeviceRule, DeviceType};
+
 #[non_exhaustive]
 #[derive(Debug, Deserialize)]
 pub struct User {
@@ -17,6 +20,67 @@ pub struct Process {
     pub user: User,
 }
 
+/// Device cgroup rule.
+///
+/// Ref: https://github.com/opencontainers/runtime-spec/blob/main/config-linux.md#allowed-device-list
+#[non_exhaustive]
+#[derive(Debug, Deserialize)]
+pub struct LinuxDeviceCgroup {
+    pub allow: bool,
+    #[serde(rename = "type")]
+    pub ty: Option<String>,
+    pub major: Option<i64>,
+    pub minor: Option<i64>,
+    pub access: Option<String>,
+}
+
+impl LinuxDeviceCgroup {
+    pub fn rule(&self) -> Result<DeviceRule> {
+        let ty = match self.ty.as_deref() {
+            None | Some("a") => None,
+            Some("b") => Some(DeviceType::Block),
+            Some("c") => Some(DeviceType::Character),
+            Some(ty) => bail!("Unknown device type `{ty}` in device cgroup rule"),
+        };
+        let number = |n: Option<i64>| match n {
+            None | Some(-1) => Ok(None),
+            Some(n) => u32::try_from(n)
+                .map(Some)
+                .with_context(|| format!("Invalid device number `{n}` in device cgroup rule")),
+        };
+        let mut access = Access::empty();
+        for c in self.access.as_deref().unwrap_or("rwm").chars() {
+            access |= match c {
+                'r' => Access::READ,
+                'w' => Access::WRITE,
+                'm' => Access::MKNOD,
+                _ => bail!("Unknown access `{c}` in device cgroup rule"),
+            };
+        }
+        Ok(DeviceRule {
+            allow: self.allow,
+            ty,
+            major: number(self.major)?,
+            minor: number(self.minor)?,
+            access,
+        })
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Default, Deserialize)]
+pub struct Resources {
+    #[serde(default)]
+    pub devices: Vec<LinuxDeviceCgroup>,
+}
+
+#[non_exhaustive]
+#[derive(Debug, Default, Deserialize)]
+pub struct Linux {
+    #[serde(default)]
+    pub resources: Resources,
+}
+
 /// OCI config.
 ///
 /// Only config that we need are implemented here.
@@ -26,6 +90,8 @@ pub struct Process {
 pub struct Config {
     pub process: Process,
     #[serde(default)]
+    pub linux: Linux,
+    #[serde(default)]
     pub annotations: HashMap<String, String>,
 }
 
@@ -42,4 +108,38 @@ impl Config {
         let config = bundle.join("config.json");
         Self::from_config(&config)
     }
+
+    /// Parse an annotation. A missing annotation is treated as the default value.
+    pub fn annotation<T: FromStr<Err = anyhow::Error> + Default>(&self, key: &str) -> Result<T> {
+        let Some(annotation) = self.annotations.get(key) else {
+            return Ok(T::default());
+        };
+        annotation
+            .parse()
+            .with_context(|| format!("Invalid `{key}` annotation"))
+    }
+
+    /// Parse a comma-separated annotation. A missing annotation is treated as an empty list.
+    pub fn annotation_list<T: FromStr<Err = anyhow::Error>>(&self, key: &str) -> Result<Vec<T>> {
+        let Some(annotation) = self.annotations.get(key) else {
+            return Ok(Vec::new());
+        };
+        annotation
+            .split(',')
+            .map(|item| {
+                item.parse()
+                    .with_context(|| format!("Invalid `{key}` annotation"))
+            })
+            .collect()
+    }
+
+    /// Device cgroup rules that the container is created with.
+    pub fn device_rules(&self) -> Result<Vec<DeviceRule>> {
+        self.linux
+            .resources
+            .devices
+            .iter()
+            .map(LinuxDeviceCgroup::rule)
+            .collect()
+    }
 }
@@ -2,7 +2,7 @@ use std::fs::{File, Permissions};
 use std::io::{BufRead, BufReader, Seek};
 use std::os::fd::AsFd;
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result, bail};
 use rustix::fs::{FileType, Mode};
@@ -12,7 +12,11 @@ use tokio::io::Interest;
 use tokio::io::unix::AsyncFd;
 use tokio::sync::Mutex;
 
-use crate::cgroup::{Access, DeviceAccessController, DeviceType};
+use crate::cgroup::{
+    Access, DenialMonitor, DeviceAccessController, DeviceCounters, DevicePermission, DeviceSet,
+    DeviceType, DeviceUsage,
+};
+use crate::cli;
 
 struct CgroupEventNotifier {
     file: AsyncFd<File>,
@@ -64,24 +68,21 @@ impl CgroupEventNotifier {
 }
 
 pub struct Container {
+    id: String,
     // Uid and gid of the primary container user.
     // Note that they're inside the user namespace (if any).
     uid: u32,
     gid: u32,
     pid: Pid,
+    cgroup: PathBuf,
     wait: tokio::sync::watch::Receiver<bool>,
     cgroup_device_filter: Mutex<DeviceAccessController>,
 }
 
 impl Container {
     pub fn new(config: &super::config::Config, state: &super::state::State) -> Result<Self> {
-        let (send, recv) = tokio::sync::watch::channel(false);
-        let mut notifier = CgroupEventNotifier::new(&state.cgroup_paths.unified)?;
-        tokio::task::spawn(async move {
-            if notifier.wait().await.is_ok() {
-                send.send_replace(true);
-            }
-        });
+        let device_filter: cli::DeviceFilter =
+            config.annotation("org.lowrisc.hotplug.device-filter")?;
 
         // runc configures systemd to also perform device filtering.
         // The removal of systemd's filtering is insufficient since after daemon-reload (or maybe
@@ -89,6 +90,7 @@ impl Container {
         // container-hotplug's operation.
         // So we'll also go ahead and remove these configuration files. Ignore errors if any since
         // the cgroup might be handled by runc directly if `--cgroup-manager=cgroupfs` is used.
+        // When cooperating, systemd's filter is kept anyway, so there is nothing to remove.
         let cgroup_name = state
             .cgroup_paths
             .unified
@@ -96,31 +98,72 @@ impl Container {
             .context("cgroup doesn't have file name")?
             .to_str()
             .context("cgroup name is not UTF-8")?;
-        let _ = std::fs::remove_file(format!(
-            "/run/systemd/transient/{cgroup_name}.d/50-DeviceAllow.conf"
-        ));
-        let _ = std::fs::remove_file(format!(
-            "/run/systemd/transient/{cgroup_name}.d/50-DevicePolicy.conf"
-        ));
+        if device_filter == cli::DeviceFilter::Replace {
+            let _ = std::fs::remove_file(format!(
+                "/run/systemd/transient/{cgroup_name}.d/50-DeviceAllow.conf"
+            ));
+            let _ = std::fs::remove_file(format!(
+                "/run/systemd/transient/{cgroup_name}.d/50-DevicePolicy.conf"
+            ));
+        }
 
         anyhow::ensure!(
             state.cgroup_paths.devices.is_none(),
             "cgroupv1 is no longer supported"
         );
 
-        let cgroup_device_filter = DeviceAccessController::new(&state.cgroup_paths.unified)?;
+        let cgroup_device_filter = DeviceAccessController::new(
+            &state.cgroup_paths.unified,
+            &config.device_rules()?,
+            device_filter,
+        )?;
+
+        let container = Self::with_filter(config, state, cgroup_device_filter)?;
+        container.remount_dev()?;
+
+        Ok(container)
+    }
+
+    /// Attach to a container created by a previous instance.
+    ///
+    /// The device filter that the previous instance has pinned is reused, and `/dev` is not
+    /// remounted again.
+    pub fn reattach(config: &super::config::Config, state: &super::state::State) -> Result<Self> {
+        let cgroup_device_filter = DeviceAccessController::from_pin(
+            &state.cgroup_paths.unified,
+            &config.device_rules()?,
+            config.annotation("org.lowrisc.hotplug.device-filter")?,
+        )?;
+
+        Self::with_filter(config, state, cgroup_device_filter)
+    }
 
-        let container = Self {
+    fn with_filter(
+        config: &super::config::Config,
+        state: &super::state::State,
+        cgroup_device_filter: DeviceAccessController,
+    ) -> Result<Self> {
+        let (send, recv) = tokio::sync::watch::channel(false);
+        let mut notifier = CgroupEventNotifier::new(&state.cgroup_paths.unified)?;
+        tokio::task::spawn(async move {
+            if notifier.wait().await.is_ok() {
+                send.send_replace(true);
+            }
+        });
+
+        Ok(Self {
+            id: state.id.clone(),
             uid: config.process.user.uid,
             gid: config.process.user.gid,
             pid: Pid::from_raw(state.init_process_pid.try_into()?).context("Invalid PID")?,
+            cgroup: state.cgroup_paths.unified.clone(),
             wait: recv,
             cgroup_device_filter: Mutex::new(cgroup_device_filter),
-        };
-
-        container.remount_dev()?;
+        })
+    }
 
-        Ok(container)
+    pub fn id(&self) -> &str {
+        &self.id
     }
 
     pub fn pid(&self) -> Pid {
@@ -277,22 +320,240 @@ impl Container {
         })?
     }
 
+    /// Target of a symlink inside the container, if it is a symlink.
+    pub async fn read_link(&self, link: &Path) -> Result<Option<PathBuf>> {
+        crate::util::namespace::MntNamespace::of_pid(self.pid)?
+            .with(|| std::fs::read_link(link).ok())
+    }
+
+    /// Device nodes under `/dev` of the container.
+    pub async fn dev_nodes(&self) -> Result<Vec<(PathBuf, DeviceType, (u32, u32))>> {
+        crate::util::namespace::MntNamespace::of_pid(self.pid)?.with(|| {
+            let mut nodes = Vec::new();
+            let mut dirs = vec![PathBuf::from("/dev")];
+            while let Some(dir) = dirs.pop() {
+                let Ok(entries) = std::fs::read_dir(&dir) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    // This does not follow symlinks.
+                    let Ok(metadata) = entry.metadata() else {
+                        continue;
+                    };
+                    let file_type = metadata.file_type();
+                    let ty = if file_type.is_dir() {
+                        dirs.push(entry.path());
+                        continue;
+                    } else if file_type.is_char_device() {
+                        DeviceType::Character
+                    } else if file_type.is_block_device() {
+                        DeviceType::Block
+                    } else {
+                        continue;
+                    };
+                    let rdev = metadata.rdev();
+                    nodes.push((
+                        entry.path(),
+                        ty,
+                        (rustix::fs::major(rdev), rustix::fs::minor(rdev)),
+                    ));
+                }
+            }
+            nodes
+        })
+    }
+
     pub async fn rm(&self, node: &Path) -> Result<()> {
         crate::util::namespace::MntNamespace::of_pid(self.pid)?.with(|| {
             let _ = std::fs::remove_file(node);
         })
     }
 
+    /// Mount the filesystem on a host block device inside the container.
+    ///
+    /// Similar to `remount_dev`, the filesystem is mounted from the init namespace so that it is
+    /// not subject to restrictions on mounting inside user namespaces, and then moved in place.
+    pub async fn mount(&self, source: &Path, mount: &cli::Mount, target: &Path) -> Result<()> {
+        let ns = crate::util::namespace::MntNamespace::of_pid(self.pid)?;
+
+        let fs = rustix::mount::fsopen(mount.fstype(), FsOpenFlags::FSOPEN_CLOEXEC)?;
+        rustix::mount::fsconfig_set_string(fs.as_fd(), "source", source)?;
+        for (key, value) in mount.parameters() {
+            match value {
+                Some(value) => rustix::mount::fsconfig_set_string(fs.as_fd(), key, value)?,
+                None => rustix::mount::fsconfig_set_flag(fs.as_fd(), key)?,
+            }
+        }
+        rustix::mount::fsconfig_create(fs.as_fd())
+            .with_context(|| format!("Cannot mount {} as {}", source.display(), mount.fstype()))?;
+        let mnt = rustix::mount::fsmount(
+            fs.as_fd(),
+            FsMountFlags::FSMOUNT_CLOEXEC,
+            mount.attributes(),
+        )?;
+
+        ns.with(|| -> Result<_> {
+            std::fs::create_dir_all(target)?;
+            rustix::mount::move_mount(
+                mnt.as_fd(),
+                "",
+                rustix::fs::CWD,
+                target,
+                MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH,
+            )?;
+            Ok(())
+        })?
+    }
+
+    /// Lazily unmount a filesystem mounted by `mount`.
+    pub async fn unmount(&self, target: &Path) -> Result<()> {
+        crate::util::namespace::MntNamespace::of_pid(self.pid)?.with(|| {
+            let _ = rustix::mount::unmount(target, UnmountFlags::DETACH);
+            let _ = std::fs::remove_dir(target);
+        })
+    }
+
+    /// Move a network interface into the container's network namespace, optionally renaming it.
+    pub async fn move_interface(&self, index: u32, name: Option<&str>) -> Result<()> {
+        let ns = crate::util::namespace::NetNamespace::of_pid(self.pid)?;
+        crate::util::rtnetlink::RtNetlink::new()?.set_link_netns(index, ns.as_fd(), name)
+    }
+
+    /// Move a network interface out of the container's network namespace back into ours,
+    /// renaming it to `host_name`.
+    pub async fn release_interface(&self, name: &str, host_name: &str) -> Result<()> {
+        let ns = crate::util::namespace::NetNamespace::of_pid(self.pid)?;
+        let host = File::open("/proc/self/ns/net")?;
+        let mut rtnetlink = crate::util::rtnetlink::RtNetlink::in_netns(ns.as_fd())?;
+        let index = rtnetlink.link_index(name)?;
+        rtnetlink.set_link_netns(index, host.as_fd(), Some(host_name))
+    }
+
+    /// Apply IO limits to a disk in the container's cgroup.
+    pub async fn set_io_limits(
+        &self,
+        (major, minor): (u32, u32),
+        limits: &[cli::IoLimit],
+    ) -> Result<()> {
+        let mut max = String::new();
+        for limit in limits {
+            match limit {
+                cli::IoLimit::Max(key, value) => max.push_str(&format!(" {key}={value}")),
+                cli::IoLimit::Weight(weight) => {
+                    std::fs::write(
+                        self.cgroup.join("io.weight"),
+                        format!("{major}:{minor} {weight}"),
+                    )
+                    .context("Cannot write io.weight")?;
+                }
+            }
+        }
+        if !max.is_empty() {
+            std::fs::write(self.cgroup.join("io.max"), format!("{major}:{minor}{max}"))
+                .context("Cannot write io.max")?;
+        }
+        Ok(())
+    }
+
+    /// Remove IO limits applied by `set_io_limits`.
+    pub async fn reset_io_limits(&self, (major, minor): (u32, u32)) -> Result<()> {
+        // The kernel drops the configuration itself when the disk is gone, so these are expected
+        // to fail if the device has been unplugged.
+        let _ = std::fs::write(
+            self.cgroup.join("io.max"),
+            format!("{major}:{minor} rbps=max wbps=max riops=max wiops=max"),
+        );
+        let _ = std::fs::write(
+            self.cgroup.join("io.weight"),
+            format!("{major}:{minor} default"),
+        );
+        Ok(())
+    }
+
     pub async fn device(
         &self,
         ty: DeviceType,
         (major, minor): (u32, u32),
         access: Access,
     ) -> Result<()> {
+        self.devices(ty, DeviceSet::Device(major, minor), access)
+            .await
+    }
+
+    /// Restrict read and write access of a device to the given users and groups of the container.
+    ///
+    /// Giving no users and groups lifts the restriction.
+    pub async fn owners(
+        &self,
+        ty: DeviceType,
+        (major, minor): (u32, u32),
+        uids: &[u32],
+        gids: &[u32],
+    ) -> Result<()> {
+        // The filter sees IDs of the initial user namespace.
+        let ns = crate::util::namespace::UserNamespace::of_pid(self.pid)?;
+        let uids = uids
+            .iter()
+            .map(|&uid| {
+                ns.uid(uid)
+                    .with_context(|| format!("UID {uid} is not mapped"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let gids = gids
+            .iter()
+            .map(|&gid| {
+                ns.gid(gid)
+                    .with_context(|| format!("GID {gid} is not mapped"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.cgroup_device_filter
+            .lock()
+            .await
+            .set_owners(ty, major, minor, &uids, &gids)
+    }
+
+    pub async fn counters(
+        &self,
+        ty: DeviceType,
+        (major, minor): (u32, u32),
+    ) -> Result<DeviceCounters> {
+        self.cgroup_device_filter
+            .lock()
+            .await
+            .counters(ty, major, minor)
+    }
+
+    pub async fn reset_counters(&self, ty: DeviceType, (major, minor): (u32, u32)) -> Result<()> {
+        self.cgroup_device_filter
+            .lock()
+            .await
+            .reset_counters(ty, major, minor)
+    }
+
+    pub async fn all_counters(&self) -> Result<Vec<DeviceUsage>> {
+        self.cgroup_device_filter.lock().await.all_counters()
+    }
+
+    pub async fn permission(&self, ty: DeviceType, (major, minor): (u32, u32)) -> Result<Access> {
+        self.cgroup_device_filter
+            .lock()
+            .await
+            .permission(ty, major, minor)
+    }
+
+    pub async fn granted(&self) -> Result<Vec<DevicePermission>> {
+        self.cgroup_device_filter.lock().await.granted()
+    }
+
+    pub async fn take_denials(&self) -> Result<Option<DenialMonitor>> {
+        self.cgroup_device_filter.lock().await.take_denials()
+    }
+
+    pub async fn devices(&self, ty: DeviceType, set: DeviceSet, access: Access) -> Result<()> {
         self.cgroup_device_filter
             .lock()
             .await
-            .set_permission(ty, major, minor, access)?;
+            .set_permission(ty, set, access)?;
         Ok(())
     }
 }
@@ -11,6 +11,13 @@ pub struct CgroupPaths {
     pub devices: Option<PathBuf>,
 }
 
+#[non_exhaustive]
+#[derive(Debug, Default, Deserialize)]
+pub struct StateConfig {
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
 /// runc `libcontainer` states.
 ///
 /// Only states that we need are implemented here.
@@ -18,8 +25,11 @@ pub struct CgroupPaths {
 #[non_exhaustive]
 #[derive(Debug, Deserialize)]
 pub struct State {
+    pub id: String,
     pub init_process_pid: u32,
     pub cgroup_paths: CgroupPaths,
+    #[serde(default)]
+    pub config: StateConfig,
 }
 
 impl State {
@@ -34,4 +44,13 @@ impl State {
     pub fn from_root_and_id(root: &Path, id: &str) -> Result<Self> {
         Self::from_state(&root.join(format!("{}/state.json", id)))
     }
+
+    /// Path of the OCI bundle that the container is created from.
+    pub fn bundle(&self) -> Option<&Path> {
+        self.config
+            .labels
+            .iter()
+            .find_map(|label| label.strip_prefix("bundle="))
+            .map(Path::new)
+    }
 }
@@ -0,0 +1,201 @@
+//! Minimal raw bpf(2) wrappers for link operations that aya does not expose.
+//!
+//! aya can attach a cgroup device program with a link, but the resulting `CgroupDeviceLink` can
+//! neither be pinned nor converted into an `FdLink`, and no aya link type exposes its file
+//! descriptor for `BPF_LINK_UPDATE`. Links are therefore created, pinned, opened and updated here,
+//! while programs and maps are still handled by aya.
+
+use std::ffi::CString;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+const BPF_OBJ_PIN: u32 = 6;
+const BPF_OBJ_GET: u32 = 7;
+const BPF_PROG_GET_FD_BY_ID: u32 = 13;
+const BPF_OBJ_GET_INFO_BY_FD: u32 = 15;
+const BPF_LINK_CREATE: u32 = 28;
+const BPF_LINK_UPDATE: u32 = 29;
+const BPF_CGROUP_DEVICE: u32 = 6;
+const BPF_F_REPLACE: u32 = 4;
+
+#[repr(C)]
+#[derive(FromBytes, Immutable, IntoBytes)]
+struct ObjPinAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, Immutable, IntoBytes)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, Immutable, IntoBytes)]
+struct LinkUpdateAttr {
+    link_fd: u32,
+    new_prog_fd: u32,
+    flags: u32,
+    old_prog_fd: u32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, Immutable, IntoBytes)]
+struct GetFdByIdAttr {
+    id: u32,
+    next_id: u32,
+    open_flags: u32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, Immutable, IntoBytes)]
+struct InfoByFdAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+/// Prefix of `struct bpf_link_info`.
+#[repr(C)]
+#[derive(Default, FromBytes, Immutable, IntoBytes)]
+struct LinkInfo {
+    ty: u32,
+    id: u32,
+    prog_id: u32,
+}
+
+/// Prefix of `struct bpf_prog_info`.
+#[repr(C)]
+#[derive(Default, FromBytes, Immutable, IntoBytes)]
+struct ProgInfo {
+    ty: u32,
+    id: u32,
+    tag: [u8; 8],
+}
+
+fn bpf<T: FromBytes + IntoBytes>(cmd: u32, attr: &mut T) -> std::io::Result<i32> {
+    let attr = attr.as_mut_bytes();
+    // SAFETY: `attr` is a valid `bpf_attr` prefix of the given size, and the kernel treats the
+    // missing tail as zeroes.
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_mut_ptr(), attr.len()) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(ret as i32)
+}
+
+/// Attach a cgroup device program to a cgroup with a bpf link.
+///
+/// The kernel rejects attach flags for cgroup links, which always allow multiple programs.
+pub fn link_create_cgroup_device(prog: BorrowedFd<'_>, cgroup: BorrowedFd<'_>) -> Result<OwnedFd> {
+    let fd = bpf(
+        BPF_LINK_CREATE,
+        &mut LinkCreateAttr {
+            prog_fd: prog.as_raw_fd() as u32,
+            target_fd: cgroup.as_raw_fd() as u32,
+            attach_type: BPF_CGROUP_DEVICE,
+            flags: 0,
+        },
+    )
+    .context("BPF_LINK_CREATE failed")?;
+    // SAFETY: The kernel returned a new file descriptor.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Pin a bpf object to bpffs.
+pub fn pin(fd: impl AsFd, path: &Path) -> Result<()> {
+    let pathname = CString::new(path.as_os_str().as_bytes())?;
+    bpf(
+        BPF_OBJ_PIN,
+        &mut ObjPinAttr {
+            pathname: pathname.as_ptr() as u64,
+            bpf_fd: fd.as_fd().as_raw_fd() as u32,
+            file_flags: 0,
+        },
+    )
+    .with_context(|| format!("Cannot pin to {}", path.display()))?;
+    Ok(())
+}
+
+/// Open a pinned bpf object.
+pub fn obj_get(path: &Path) -> Result<OwnedFd> {
+    let pathname = CString::new(path.as_os_str().as_bytes())?;
+    let fd = bpf(
+        BPF_OBJ_GET,
+        &mut ObjPinAttr {
+            pathname: pathname.as_ptr() as u64,
+            bpf_fd: 0,
+            file_flags: 0,
+        },
+    )
+    .with_context(|| format!("Cannot open pinned object {}", path.display()))?;
+    // SAFETY: The kernel returned a new file descriptor.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn info<T: FromBytes + IntoBytes>(fd: BorrowedFd<'_>, info: &mut T) -> Result<()> {
+    bpf(
+        BPF_OBJ_GET_INFO_BY_FD,
+        &mut InfoByFdAttr {
+            bpf_fd: fd.as_raw_fd() as u32,
+            info_len: size_of::<T>() as u32,
+            info: info.as_mut_bytes().as_mut_ptr() as u64,
+        },
+    )
+    .context("BPF_OBJ_GET_INFO_BY_FD failed")?;
+    Ok(())
+}
+
+/// Open the program currently attached with a bpf link.
+pub fn link_prog(link: impl AsFd) -> Result<OwnedFd> {
+    let mut link_info = LinkInfo::default();
+    info(link.as_fd(), &mut link_info)?;
+    let fd = bpf(
+        BPF_PROG_GET_FD_BY_ID,
+        &mut GetFdByIdAttr {
+            id: link_info.prog_id,
+            next_id: 0,
+            open_flags: 0,
+        },
+    )
+    .context("BPF_PROG_GET_FD_BY_ID failed")?;
+    // SAFETY: The kernel returned a new file descriptor.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Tag of a loaded program, which is a hash of its instructions.
+pub fn prog_tag(prog: impl AsFd) -> Result<[u8; 8]> {
+    let mut prog_info = ProgInfo::default();
+    info(prog.as_fd(), &mut prog_info)?;
+    Ok(prog_info.tag)
+}
+
+/// Atomically replace the program attached with a bpf link.
+///
+/// This fails if `old_prog` is no longer the attached program.
+pub fn link_update(
+    link: BorrowedFd<'_>,
+    new_prog: BorrowedFd<'_>,
+    old_prog: BorrowedFd<'_>,
+) -> Result<()> {
+    bpf(
+        BPF_LINK_UPDATE,
+        &mut LinkUpdateAttr {
+            link_fd: link.as_raw_fd() as u32,
+            new_prog_fd: new_prog.as_raw_fd() as u32,
+            flags: BPF_F_REPLACE,
+            old_prog_fd: old_prog.as_raw_fd() as u32,
+        },
+    )
+    .context("BPF_LINK_UPDATE failed")?;
+    Ok(())
+}
@@ -1,3 +1,5 @@
+pub mod bpf;
 pub mod escape;
 pub mod log;
 pub mod namespace;
+pub mod rtnetlink;
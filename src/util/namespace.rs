@@ -1,6 +1,6 @@
 use std::fs::File;
 use std::ops::Deref;
-use std::os::fd::AsFd;
+use std::os::fd::{AsFd, BorrowedFd};
 use std::path::Path;
 
 use anyhow::{Context, Result};
@@ -198,3 +198,9 @@ impl NetNamespace {
         })
     }
 }
+
+impl AsFd for NetNamespace {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.net_fd.as_fd()
+    }
+}
@@ -0,0 +1,172 @@
+//! Minimal rtnetlink client, just enough to move network interfaces between namespaces.
+
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+
+use anyhow::{Context, Result, bail, ensure};
+use rustix::net::{AddressFamily, RecvFlags, SendFlags, SocketType, netlink::SocketAddrNetlink};
+use rustix::thread::LinkNameSpaceType;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+const RTM_NEWLINK: u16 = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_NET_NS_FD: u16 = 28;
+
+#[repr(C)]
+#[derive(Immutable, IntoBytes, FromBytes, KnownLayout)]
+struct NetlinkHeader {
+    len: u32,
+    ty: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+#[repr(C)]
+#[derive(Immutable, IntoBytes)]
+struct InterfaceInfo {
+    family: u8,
+    pad: u8,
+    ty: u16,
+    index: i32,
+    flags: u32,
+    change: u32,
+}
+
+#[repr(C)]
+#[derive(Immutable, IntoBytes)]
+struct Attribute {
+    len: u16,
+    ty: u16,
+}
+
+fn push_attribute(message: &mut Vec<u8>, ty: u16, payload: &[u8]) {
+    let attr = Attribute {
+        len: (size_of::<Attribute>() + payload.len()) as u16,
+        ty,
+    };
+    message.extend_from_slice(attr.as_bytes());
+    message.extend_from_slice(payload);
+    // Attributes are aligned to 4 bytes.
+    message.resize(message.len().next_multiple_of(4), 0);
+}
+
+pub struct RtNetlink {
+    socket: OwnedFd,
+    seq_num: u32,
+}
+
+impl RtNetlink {
+    pub fn new() -> Result<Self> {
+        // NETLINK_ROUTE is protocol 0.
+        let socket = rustix::net::socket(AddressFamily::NETLINK, SocketType::RAW, None)?;
+        Ok(Self { socket, seq_num: 0 })
+    }
+
+    /// Open a socket in another network namespace, to manage the interfaces inside it.
+    ///
+    /// Only the network namespace is entered, so that the socket keeps the privileges of this
+    /// process, e.g. to move interfaces out of the namespace again.
+    pub fn in_netns(netns: BorrowedFd<'_>) -> Result<Self> {
+        // Switching namespaces only affects the current thread, so do it in a new one.
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| -> Result<Self> {
+                    rustix::thread::move_into_link_name_space(
+                        netns,
+                        Some(LinkNameSpaceType::Network),
+                    )?;
+                    Self::new()
+                })
+                .join()
+                .map_err(|_| anyhow::anyhow!("work thread panicked"))?
+        })
+    }
+
+    /// Index of a network interface in the namespace of the socket.
+    pub fn link_index(&self, name: &str) -> Result<u32> {
+        rustix::net::netdevice::name_to_index(&self.socket, name)
+            .with_context(|| format!("Cannot find network interface {name}"))
+    }
+
+    /// Move a network interface to another network namespace, optionally renaming it.
+    pub fn set_link_netns(
+        &mut self,
+        index: u32,
+        netns: BorrowedFd<'_>,
+        name: Option<&str>,
+    ) -> Result<()> {
+        self.seq_num += 1;
+
+        let mut message = Vec::new();
+        message.extend_from_slice(
+            NetlinkHeader {
+                len: 0,
+                ty: RTM_NEWLINK,
+                flags: NLM_F_REQUEST | NLM_F_ACK,
+                seq: self.seq_num,
+                pid: 0,
+            }
+            .as_bytes(),
+        );
+        message.extend_from_slice(
+            InterfaceInfo {
+                family: 0,
+                pad: 0,
+                ty: 0,
+                index: index.try_into()?,
+                flags: 0,
+                change: 0,
+            }
+            .as_bytes(),
+        );
+        push_attribute(
+            &mut message,
+            IFLA_NET_NS_FD,
+            &(netns.as_raw_fd() as u32).to_ne_bytes(),
+        );
+        if let Some(name) = name {
+            // The kernel will rename the interface in the target namespace.
+            let mut payload = name.as_bytes().to_vec();
+            payload.push(0);
+            push_attribute(&mut message, IFLA_IFNAME, &payload);
+        }
+        let len = message.len() as u32;
+        message[..4].copy_from_slice(&len.to_ne_bytes());
+
+        rustix::net::sendto(
+            &self.socket,
+            &message,
+            SendFlags::empty(),
+            &SocketAddrNetlink::new(0, 0),
+        )?;
+
+        self.ack()
+    }
+
+    fn ack(&self) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, _) = rustix::net::recv(self.socket.as_fd(), &mut buf, RecvFlags::empty())?;
+            let (header, payload) = NetlinkHeader::read_from_prefix(&buf[..len])
+                .ok()
+                .context("truncated netlink message")?;
+            if header.seq != self.seq_num {
+                continue;
+            }
+            ensure!(header.ty == NLMSG_ERROR, "unexpected netlink message");
+            let errno = i32::from_ne_bytes(
+                payload
+                    .get(..4)
+                    .context("truncated netlink error")?
+                    .try_into()?,
+            );
+            if errno != 0 {
+                bail!(std::io::Error::from_raw_os_error(-errno));
+            }
+            return Ok(());
+        }
+    }
+}